@@ -0,0 +1,21 @@
+use linux_embedded_hal::Serial;
+use winsen_co2_sensor::logger::{CsvSink, JsonLinesSink, Logger, Rotation};
+use winsen_co2_sensor::WinsenSensor;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let serial_path = std::env::args().nth(1).unwrap_or("/dev/ttyUSB0".into());
+    let serial = Serial::open(serial_path).unwrap();
+    let sensor = WinsenSensor::new(serial, Instant::now());
+
+    let mut logger = Logger::new(sensor, Duration::from_secs(10))
+        .sink(CsvSink::new("logs", "co2", Rotation::Daily))
+        .sink(JsonLinesSink::new(std::io::stdout()));
+    logger.run(|_, errors| {
+        for e in errors {
+            eprintln!("warning: {}", e);
+        }
+        ControlFlow::<()>::Continue(())
+    });
+}
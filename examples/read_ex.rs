@@ -14,6 +14,8 @@ fn main() {
     }
 
     println!("CO2: {}", sensor.read_co2_concentration().unwrap());
+    println!("measurement: {:?}", sensor.read_measurement().unwrap());
+    println!("unlimited measurement: {:?}", sensor.read_unlimited_measurement().unwrap());
     println!("detection range: {}", sensor.get_detection_range().unwrap());
     println!("analog bounds: {:?}", sensor.get_analog_bounds().unwrap());
     println!("fw version: {:?}", sensor.get_firmware_version().unwrap());
@@ -0,0 +1,22 @@
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio_serial::SerialPortBuilderExt;
+use winsen_co2_sensor::TokioWinsenSensor;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let serial_path = std::env::args().nth(1).unwrap_or("/dev/ttyUSB0".into());
+    let serial = tokio_serial::new(serial_path, 9600).open_native_async().unwrap();
+    let mut sensor = TokioWinsenSensor::from_tokio(serial);
+
+    let probe_ok = sensor.probe().await.unwrap();
+    println!("Probe: {:?}", probe_ok);
+    if !probe_ok {
+        return;
+    }
+
+    let mut measurements = Box::pin(sensor.measurements(Duration::from_secs(5)));
+    while let Some(measurement) = measurements.next().await {
+        println!("measurement: {:?}", measurement);
+    }
+}
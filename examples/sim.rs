@@ -0,0 +1,22 @@
+use winsen_co2_sensor::WinsenSensor;
+use winsen_co2_sensor::sim::{Co2Curve, SimulatedSensor, StepCounter};
+
+fn main() {
+    let emulator = SimulatedSensor::new(Co2Curve::Ramp { from: 400, to: 6000, samples: 10 });
+    let mut sensor = WinsenSensor::new(emulator, StepCounter::default());
+
+    let probe_ok = sensor.probe().unwrap();
+    println!("Probe: {:?}", probe_ok);
+
+    sensor.set_automatic_baseline_correction(false).unwrap();
+    for _ in 0..10 {
+        println!("measurement: {:?}", sensor.read_measurement().unwrap());
+    }
+    println!("unlimited measurement: {:?}", sensor.read_unlimited_measurement().unwrap());
+    println!("detection range: {}", sensor.get_detection_range().unwrap());
+    println!("analog bounds: {:?}", sensor.get_analog_bounds().unwrap());
+    println!("fw version: {:?}", sensor.get_firmware_version().unwrap());
+
+    let (emulator, _) = sensor.free();
+    println!("ABC: {:?}", emulator.automatic_baseline_correction());
+}
@@ -0,0 +1,454 @@
+//! Async driver built on `embedded-io-async` and `embedded-hal-async`.
+
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{Read, Write};
+
+use crate::{Concentration, Config, Error, RetryPolicy};
+use crate::config::DISCARD_LIMIT;
+use crate::protocol::{Co2Concentration, Command, Frame, FrameFinder, Measurement, Request, Response, FRAME_LENGTH};
+#[cfg(feature = "experimental")]
+use crate::protocol::{AnalogBounds, DetectionRange, FirmwareVersion, UnlimitedMeasurement};
+
+/// Async counterpart of [`WinsenSensor`](crate::WinsenSensor)
+///
+/// Timeouts are implemented by racing serial transfers against `delay`, so no
+/// CPU time is spent waiting for the sensor.
+pub struct AsyncWinsenSensor<S, D> {
+    serial: S,
+    delay: D,
+    config: Config,
+}
+
+impl<S: Read + Write, D: DelayNs> AsyncWinsenSensor<S, D> {
+    pub fn new(serial: S, delay: D) -> Self {
+        Self::with_config(serial, delay, Config::default())
+    }
+
+    pub fn with_config(serial: S, delay: D, config: Config) -> Self {
+        Self {
+            serial,
+            delay,
+            config,
+        }
+    }
+
+    pub fn free(self) -> (S, D) {
+        (self.serial, self.delay)
+    }
+
+    /// Returns the current configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace the configuration
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub async fn probe(&mut self) -> Result<bool, S::Error> {
+        // Use "Read CO2 concentration" command, ignore the result
+        match self.transaction_with(Request::ReadCo2Concentration, self.config.retry.for_probe()).await {
+            Ok(_) => Ok(true),
+            Err(Error::Serial(e)) => Err(e),
+            Err(_) => Ok(false),
+        }
+    }
+
+    async fn send_packet(&mut self, frame: &Frame) -> Result<(), Error<S::Error>> {
+        let serial = &mut self.serial;
+        let transfer = async {
+            serial.write_all(frame.as_bytes()).await?;
+            serial.flush().await
+        };
+        with_timeout(&mut self.delay, self.config.send_timeout_ms, transfer).await?.map_err(Error::Serial)
+    }
+
+    /// Discard bytes received before the request is sent
+    async fn flush_rx(&mut self) -> Result<(), Error<S::Error>> {
+        for _ in 0..DISCARD_LIMIT {
+            // Zero timeout: only take bytes which are already available
+            match with_timeout(&mut self.delay, 0, read_byte(&mut self.serial)).await.and_then(|r| r) {
+                Ok(_) => {},
+                Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_packet(&mut self, command: Command) -> Result<Frame, Error<S::Error>> {
+        let mut finder = FrameFinder::new(command);
+        let mut last_error = None;
+
+        // Wait for the first byte of the response, then for each of the following bytes
+        let mut timeout_ms = self.config.response_timeout_ms;
+        for _ in 0..DISCARD_LIMIT + FRAME_LENGTH {
+            let byte = match with_timeout(&mut self.delay, timeout_ms, read_byte(&mut self.serial)).await.and_then(|r| r) {
+                Ok(byte) => byte,
+                Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            };
+            timeout_ms = self.config.inter_byte_timeout_ms;
+
+            match finder.push(byte) {
+                Some(Ok(frame)) => return Ok(frame),
+                // Keep looking for the right frame, but report the error if there is none
+                Some(Err(e)) => last_error = Some(e),
+                None => {},
+            }
+        }
+
+        Err(last_error.map_or(Error::Timeout, Error::from))
+    }
+
+    /// Send the request and receive the response, retrying according to the retry policy
+    async fn transaction(&mut self, request: Request) -> Result<Frame, Error<S::Error>> {
+        self.transaction_with(request, self.config.retry).await
+    }
+
+    /// Send the request and receive the response, retrying according to `retry`
+    async fn transaction_with(&mut self, request: Request, retry: RetryPolicy) -> Result<Frame, Error<S::Error>> {
+        let frame = request.encode();
+
+        let mut attempt = 0;
+        loop {
+            let result = self.exchange(&frame, request.command()).await;
+            match result {
+                Err(ref e) if attempt < retry.retries && retry.is_retryable(e) => {
+                    self.delay.delay_ms(retry.backoff_ms(attempt)).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    async fn exchange(&mut self, frame: &Frame, command: Command) -> Result<Frame, Error<S::Error>> {
+        if self.config.flush_rx {
+            self.flush_rx().await?;
+        }
+        self.send_packet(frame).await?;
+        self.receive_packet(command).await
+    }
+
+    async fn command<R: Response>(&mut self, request: Request) -> Result<R, Error<S::Error>> {
+        Ok(self.transaction(request).await?.decode()?)
+    }
+
+    async fn simple_command(&mut self, request: Request) -> Result<(), Error<S::Error>> {
+        self.transaction(request).await.map(|_| ())
+    }
+
+    /// Read the CO2 gas concentration
+    pub async fn read_co2_concentration(&mut self) -> Result<Concentration, Error<S::Error>> {
+        let response: Co2Concentration = self.command(Request::ReadCo2Concentration).await?;
+        Ok(response.ppm.into())
+    }
+
+    /// Read the CO2 gas concentration along with the module temperature and status
+    pub async fn read_measurement(&mut self) -> Result<Measurement, Error<S::Error>> {
+        self.command(Request::ReadCo2Concentration).await
+    }
+
+    /// Enable or disable Automatic Baseline Correction (ABC)
+    pub async fn set_automatic_baseline_correction(&mut self, enabled: bool) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::SetAutomaticBaselineCorrection(enabled)).await
+    }
+
+    /// Perform zero point calibration
+    ///
+    /// See [`WinsenSensor::calibrate_zero_point`](crate::WinsenSensor::calibrate_zero_point).
+    pub async fn calibrate_zero_point(&mut self) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::CalibrateZeroPoint).await
+    }
+
+    /// Perform span point calibration
+    ///
+    /// See [`WinsenSensor::calibrate_span_point`](crate::WinsenSensor::calibrate_span_point).
+    pub async fn calibrate_span_point(&mut self, span: Concentration) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::CalibrateSpanPoint(span.ppm_u16())).await
+    }
+
+    /// Set the sensor detection range (MH-Z19B only).
+    ///
+    /// Quoting the datasheet: "Detection range is 2000 or 5000ppm"
+    pub async fn set_detection_range(&mut self, range: Concentration) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::SetDetectionRange(range.ppm())).await
+    }
+
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Read the CO2 gas concentration without clamping it to the detection range
+    pub async fn read_unlimited_measurement(&mut self) -> Result<UnlimitedMeasurement, Error<S::Error>> {
+        self.command(Request::ReadUnlimitedCo2).await
+    }
+
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Get the sensor detection range
+    pub async fn get_detection_range(&mut self) -> Result<Concentration, Error<S::Error>> {
+        let response: DetectionRange = self.command(Request::GetDetectionRange).await?;
+        Ok(Concentration::from_ppm(response.range))
+    }
+
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Perform sensor reset
+    ///
+    /// This command resets sensor MCU
+    pub async fn reset(&mut self) -> Result<(), Error<S::Error>> {
+        self.send_packet(&Request::Reset.encode()).await
+    }
+
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Get CO2 concentration bounds used for the analog output
+    pub async fn get_analog_bounds(&mut self) -> Result<(Concentration, Concentration), Error<S::Error>> {
+        let response: AnalogBounds = self.command(Request::GetAnalogBounds).await?;
+        Ok((response.high.into(), response.low.into()))
+    }
+
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Get firmware version string
+    pub async fn get_firmware_version(&mut self) -> Result<[u8; 4], Error<S::Error>> {
+        let response: FirmwareVersion = self.command(Request::GetFirmwareVersion).await?;
+        Ok(response.version)
+    }
+}
+
+async fn read_byte<S: Read>(serial: &mut S) -> Result<u8, Error<S::Error>> {
+    let mut byte = [0u8; 1];
+    match serial.read(&mut byte).await {
+        Ok(1) => Ok(byte[0]),
+        // The stream is closed, no response will ever arrive
+        Ok(_) => Err(Error::Timeout),
+        Err(e) => Err(Error::Serial(e)),
+    }
+}
+
+/// Run `future` to completion unless `ms` milliseconds elapse first
+async fn with_timeout<D: DelayNs, F: Future, E>(delay: &mut D, ms: u32, future: F) -> Result<F::Output, Error<E>> {
+    let mut future = pin!(future);
+    let mut timeout = pin!(delay.delay_ms(ms));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        if timeout.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(Error::Timeout));
+        }
+        Poll::Pending
+    }).await
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use core::task::{Context, Waker};
+
+    use super::*;
+    use crate::sim::{Co2Curve, NoDelay, SimulatedSensor};
+
+    fn sensor(curve: Co2Curve) -> AsyncWinsenSensor<SimulatedSensor, NoDelay> {
+        AsyncWinsenSensor::new(SimulatedSensor::new(curve), NoDelay)
+    }
+
+    fn sensor_with_retries(retries: u8) -> AsyncWinsenSensor<SimulatedSensor, NoDelay> {
+        let config = Config::default().retry(RetryPolicy::fixed(retries, 10));
+        AsyncWinsenSensor::with_config(SimulatedSensor::new(Co2Curve::Constant(800)), NoDelay, config)
+    }
+
+    /// Poll `future` until it completes, the simulated sensor and delay never need a wakeup
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Send `request` behind the driver's back, leaving the answer in the receive queue
+    fn send_unread(sensor: &mut AsyncWinsenSensor<SimulatedSensor, NoDelay>, request: Request) {
+        block_on(sensor.serial.write_all(request.encode().as_bytes())).unwrap();
+    }
+
+    #[test]
+    fn read_co2_concentration() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn read_measurement() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_temperature(31);
+        sensor.serial.set_status(0x40);
+        let measurement = block_on(sensor.read_measurement()).unwrap();
+        assert_eq!(measurement.co2_ppm, 800);
+        assert_eq!(measurement.temperature_c, 31);
+        assert_eq!(measurement.status, 0x40);
+    }
+
+    #[test]
+    fn set_automatic_baseline_correction() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        block_on(sensor.set_automatic_baseline_correction(false)).unwrap();
+        assert!(!sensor.serial.automatic_baseline_correction());
+        block_on(sensor.set_automatic_baseline_correction(true)).unwrap();
+        assert!(sensor.serial.automatic_baseline_correction());
+    }
+
+    #[test]
+    fn calibrate_zero_and_span_point() {
+        let mut sensor = sensor(Co2Curve::Constant(450));
+        block_on(sensor.calibrate_zero_point()).unwrap();
+        assert_eq!(sensor.serial.zero_calibrations(), 1);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(400)));
+
+        sensor.serial.set_curve(Co2Curve::Constant(1900));
+        block_on(sensor.calibrate_span_point(Concentration::from_ppm(2000))).unwrap();
+        assert_eq!(sensor.serial.span_calibrations(), 1);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(2000)));
+    }
+
+    #[test]
+    fn set_detection_range() {
+        let mut sensor = sensor(Co2Curve::Constant(3000));
+        block_on(sensor.set_detection_range(Concentration::from_ppm(2000))).unwrap();
+        assert_eq!(sensor.serial.detection_range(), 2000);
+        // Readings are clamped to the detection range
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(2000)));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn get_detection_range_and_analog_bounds() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        block_on(sensor.set_detection_range(Concentration::from_ppm(2000))).unwrap();
+        assert_eq!(block_on(sensor.get_detection_range()), Ok(Concentration::from_ppm(2000)));
+        assert_eq!(block_on(sensor.get_analog_bounds()), Ok((Concentration::from_ppm(2000), Concentration::from_ppm(0))));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn read_unlimited_measurement() {
+        let mut sensor = sensor(Co2Curve::Constant(3000));
+        block_on(sensor.set_detection_range(Concentration::from_ppm(2000))).unwrap();
+        sensor.serial.set_temperature(24);
+        sensor.serial.set_min_light_adc(12345);
+        let measurement = block_on(sensor.read_unlimited_measurement()).unwrap();
+        assert_eq!(measurement.co2_ppm, 3000);
+        assert_eq!(measurement.temperature_c(), 24.0);
+        assert_eq!(measurement.min_light_adc, 12345);
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn get_firmware_version() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_firmware_version(*b"0430");
+        assert_eq!(block_on(sensor.get_firmware_version()), Ok(*b"0430"));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn reset_gets_no_response() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        block_on(sensor.reset()).unwrap();
+        assert_eq!(sensor.serial.resets(), 1);
+        assert_eq!(sensor.serial.pending(), 0);
+        // The next request isn't confused by the reset
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn probe() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        assert_eq!(block_on(sensor.probe()), Ok(true));
+    }
+
+    #[test]
+    fn probe_skips_bootloader_prompt() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_bootloader_frames(2);
+        assert_eq!(block_on(sensor.probe()), Ok(true));
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn probe_retries_do_not_multiply() {
+        // Three attempts with the bootloader retries counting against the configured ones
+        let mut sensor = sensor_with_retries(2);
+        sensor.serial.set_bootloader_frames(4);
+        assert_eq!(block_on(sensor.probe()), Ok(false));
+        sensor.set_config(Config::default());
+        assert_eq!(block_on(sensor.read_co2_concentration()), Err(Error::Timeout));
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_bootloader_frames(1);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn retries_after_timeout() {
+        let mut sensor = sensor_with_retries(2);
+        sensor.serial.set_bootloader_frames(2);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+        assert_eq!(sensor.serial.samples(), 1);
+    }
+
+    #[test]
+    fn retries_are_limited() {
+        let mut sensor = sensor_with_retries(2);
+        sensor.serial.set_bootloader_frames(3);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Err(Error::Timeout));
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        // The bootloader prompt is still queued when the request is sent
+        sensor.serial.set_bootloader_frames(1);
+        send_unread(&mut sensor, Request::ReadCo2Concentration);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+        assert_eq!(sensor.serial.pending(), 0);
+    }
+
+    #[test]
+    fn stale_response_is_skipped() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        send_unread(&mut sensor, Request::GetDetectionRange);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Ok(Concentration::from_ppm(800)));
+        assert_eq!(sensor.serial.pending(), 0);
+    }
+
+    #[test]
+    fn stale_response_alone_is_wrong_packet_type() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        send_unread(&mut sensor, Request::GetDetectionRange);
+        sensor.serial.set_bootloader_frames(1);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Err(Error::WrongPacketType));
+    }
+
+    #[test]
+    fn flush_rx_discards_stale_response() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.set_config(Config::default().flush_rx(true));
+        send_unread(&mut sensor, Request::GetDetectionRange);
+        sensor.serial.set_bootloader_frames(1);
+        assert_eq!(block_on(sensor.read_co2_concentration()), Err(Error::Timeout));
+        assert_eq!(sensor.serial.pending(), 0);
+    }
+}
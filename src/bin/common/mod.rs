@@ -0,0 +1,80 @@
+//! Command-line parsing and output helpers shared by the binaries.
+
+use std::env;
+use std::fmt::Write;
+use std::iter::Skip;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Exit code for invalid command lines
+pub const EXIT_USAGE: i32 = 2;
+
+/// Command-line arguments of a binary described by `usage`
+pub struct Args {
+    usage: &'static str,
+    args: Skip<env::Args>,
+}
+
+impl Args {
+    pub fn new(usage: &'static str) -> Self {
+        Args {
+            usage,
+            args: env::args().skip(1),
+        }
+    }
+
+    /// Print `message` and the usage, then exit with [`EXIT_USAGE`]
+    pub fn error(&self, message: &str) -> ! {
+        eprintln!("error: {}\n\n{}", message, self.usage);
+        exit(EXIT_USAGE);
+    }
+
+    /// Parse the value of `name`
+    pub fn parse<T: FromStr>(&self, name: &str, value: &str) -> T {
+        value.parse().unwrap_or_else(|_| self.error(&format!("invalid value for {}: {}", name, value)))
+    }
+
+    /// Parse the argument following option `name`
+    pub fn value<T: FromStr>(&mut self, name: &str) -> T {
+        match self.args.next() {
+            Some(value) => self.parse(name, &value),
+            None => self.error(&format!("missing value for {}", name)),
+        }
+    }
+
+    /// Parse the argument following option `name` as seconds, rejecting negative, infinite and NaN values
+    pub fn duration(&mut self, name: &str) -> Duration {
+        let value: String = self.value(name);
+        value.parse().ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .unwrap_or_else(|| self.error(&format!("invalid value for {}: {}", name, value)))
+    }
+}
+
+impl Iterator for Args {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+}
+
+/// Quote a string for JSON output
+#[allow(dead_code)] // Not every binary writes JSON
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
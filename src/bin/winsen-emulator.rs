@@ -0,0 +1,278 @@
+//! MH-Z19B emulator on a pseudo-terminal.
+//!
+//! Creates a PTY which behaves like a sensor connected through a USB-serial adapter,
+//! so that programs using `linux_embedded_hal::Serial::open` can be tested without hardware.
+
+mod common;
+
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use embedded_hal::serial::{Read, Write};
+use winsen_co2_sensor::fault::{Faults, FaultySerial};
+use winsen_co2_sensor::sim::{Co2Curve, SimulatedSensor};
+
+use crate::common::Args;
+
+const USAGE: &str = "\
+Usage: winsen-emulator [OPTIONS]
+
+Emulates an MH-Z19B on a pseudo-terminal and prints its path.
+
+Options:
+  --co2 <PPM>                 Constant CO2 concentration [default: 800]
+  --ramp <FROM:TO:SAMPLES>    Linear CO2 ramp
+  --sequence <PPM,PPM,...>    Cycle through CO2 values
+  --temperature <C>           Module temperature [default: 25]
+  --range <PPM>               Detection range [default: 5000]
+  --firmware <VERSION>        Firmware version string, 4 characters [default: 0443]
+  --preheat <SECONDS>         Report a placeholder value after start [default: 0]
+  --preheat-value <PPM>       Placeholder value reported while preheating [default: 500]
+  --bootloader-frames <N>     Answer the first N requests with a bootloader prompt [default: 0]
+  --corrupt <PROBABILITY>     Corrupt each transmitted byte with this probability [default: 0]
+  --drop <PROBABILITY>        Drop each transmitted byte with this probability [default: 0]
+  --drop-responses <PROBABILITY>
+                              Drop each whole response with this probability [default: 0]
+  --seed <N>                  Random seed for corruption and drops [default: 1]
+  --link <PATH>               Create a symlink to the PTY at PATH
+  -h, --help                  Print this help
+";
+
+struct Options {
+    curve: Co2Curve,
+    temperature: i16,
+    range: u32,
+    firmware: [u8; 4],
+    preheat: Duration,
+    preheat_value: u16,
+    bootloader_frames: u32,
+    corrupt: f32,
+    drop: f32,
+    drop_responses: f32,
+    seed: u32,
+    link: Option<PathBuf>,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        curve: Co2Curve::Constant(800),
+        temperature: 25,
+        range: 5000,
+        firmware: *b"0443",
+        preheat: Duration::from_secs(0),
+        preheat_value: 500,
+        bootloader_frames: 0,
+        corrupt: 0.0,
+        drop: 0.0,
+        drop_responses: 0.0,
+        seed: 1,
+        link: None,
+    };
+
+    let mut args = Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            },
+            "--co2" => options.curve = Co2Curve::Constant(args.value(&arg)),
+            "--ramp" => {
+                let value: String = args.value(&arg);
+                let parts: Vec<&str> = value.split(':').collect();
+                if parts.len() != 3 {
+                    args.error(&format!("invalid value for {}: {}", arg, value));
+                }
+                options.curve = Co2Curve::Ramp {
+                    from: args.parse(&arg, parts[0]),
+                    to: args.parse(&arg, parts[1]),
+                    samples: args.parse(&arg, parts[2]),
+                };
+            },
+            "--sequence" => {
+                let value: String = args.value(&arg);
+                let values: Vec<u16> = value.split(',').map(|v| args.parse(&arg, v)).collect();
+                options.curve = Co2Curve::Sequence(Box::leak(values.into_boxed_slice()));
+            },
+            "--temperature" => options.temperature = args.value(&arg),
+            "--range" => options.range = args.value(&arg),
+            "--firmware" => {
+                let value: String = args.value(&arg);
+                if value.len() != 4 {
+                    args.error(&format!("invalid value for {}: {}", arg, value));
+                }
+                options.firmware.copy_from_slice(value.as_bytes());
+            },
+            "--preheat" => options.preheat = args.duration(&arg),
+            "--preheat-value" => options.preheat_value = args.value(&arg),
+            "--bootloader-frames" => options.bootloader_frames = args.value(&arg),
+            "--corrupt" => options.corrupt = probability(&mut args, &arg),
+            "--drop" => options.drop = probability(&mut args, &arg),
+            "--drop-responses" => options.drop_responses = probability(&mut args, &arg),
+            "--seed" => options.seed = args.value(&arg),
+            "--link" => options.link = Some(args.value::<String>(&arg).into()),
+            _ => args.error(&format!("unknown option: {}", arg)),
+        }
+    }
+    options
+}
+
+/// Parse the argument following option `name` as a probability between 0 and 1
+fn probability(args: &mut Args, name: &str) -> f32 {
+    let value: f32 = args.value(name);
+    if !(0.0..=1.0).contains(&value) {
+        args.error(&format!("invalid value for {}: {}", name, value));
+    }
+    value
+}
+
+/// Xorshift32 generator deciding which responses are dropped
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // Zero is a fixed point of xorshift, and the seed already drives the byte faults
+        Rng(!seed | 1)
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        probability > 0.0 && ((self.0 >> 8) as f32) < probability * (1u32 << 24) as f32
+    }
+}
+
+/// Pseudo-terminal pair
+struct Pty {
+    master: libc::c_int,
+    // Kept open so that reads from the master don't fail while no client is connected
+    _slave: libc::c_int,
+    path: PathBuf,
+}
+
+impl Pty {
+    fn open() -> io::Result<Pty> {
+        let mut master = 0;
+        let mut slave = 0;
+        let mut name = [0 as libc::c_char; 64];
+        let ret = unsafe {
+            libc::openpty(&mut master, &mut slave, name.as_mut_ptr(), std::ptr::null(), std::ptr::null())
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Raw mode, the way a client would configure a real serial port
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(slave, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned().into();
+        Ok(Pty {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+
+    /// Wait for at most `timeout` and read available bytes
+    fn read(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let mut fds = libc::pollfd {
+            fd: self.master,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ret == 0 {
+            return Ok(0);
+        }
+        let ret = unsafe { libc::read(self.master, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn write_all(&self, mut buffer: &[u8]) -> io::Result<()> {
+        while !buffer.is_empty() {
+            let ret = unsafe { libc::write(self.master, buffer.as_ptr() as *const libc::c_void, buffer.len()) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            buffer = &buffer[ret as usize..];
+        }
+        Ok(())
+    }
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let mut sim = SimulatedSensor::new(options.curve);
+    sim.set_temperature(options.temperature);
+    sim.set_detection_range(options.range);
+    sim.set_firmware_version(options.firmware);
+    sim.set_bootloader_frames(options.bootloader_frames);
+    let faults = Faults {
+        drop: options.drop,
+        corrupt: options.corrupt,
+        ..Faults::none()
+    };
+    let mut sensor = FaultySerial::new(sim, faults, Faults::none(), options.seed);
+    let mut responses = Rng::new(options.seed);
+
+    let pty = Pty::open()?;
+    if let Some(link) = &options.link {
+        // Replace a link left over from a previous run
+        let _ = std::fs::remove_file(link);
+        symlink(&pty.path, link)?;
+    }
+    println!("{}", pty.path.display());
+
+    let started = Instant::now();
+    let mut buffer = [0u8; 64];
+    loop {
+        let preheating = started.elapsed() < options.preheat;
+        sensor.inner_mut().set_preheat(if preheating { Some(options.preheat_value) } else { None });
+
+        let n = pty.read(&mut buffer, Duration::from_millis(10))?;
+        for b in &buffer[..n] {
+            let _ = sensor.write(*b);
+
+            // Collect the answer to the request completed by this byte, if any
+            let mut response = Vec::new();
+            loop {
+                match sensor.read() {
+                    Ok(b) => response.push(b),
+                    // The byte was dropped, keep reading the rest of the answer
+                    Err(_) if sensor.inner().pending() > 0 => {},
+                    Err(_) => break,
+                }
+            }
+            if response.is_empty() || !responses.chance(options.drop_responses) {
+                pty.write_all(&response)?;
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = parse_options();
+    if let Err(e) = run(options) {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
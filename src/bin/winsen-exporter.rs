@@ -0,0 +1,280 @@
+//! Prometheus exporter daemon.
+//!
+//! Samples the sensor periodically and serves the latest values on `/metrics`.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use linux_embedded_hal::Serial;
+use winsen_co2_sensor::{Config, Error, Measurement, RetryPolicy, WinsenSensor};
+
+use crate::common::Args;
+
+const USAGE: &str = "\
+Usage: winsen-exporter [OPTIONS]
+
+Serves sensor readings as Prometheus metrics on /metrics.
+
+Options:
+  -p, --port <PATH>         Serial port [default: /dev/ttyUSB0]
+  -l, --listen <ADDRESS>    HTTP listen address [default: 127.0.0.1:9942]
+  -i, --interval <SECONDS>  Sampling interval [default: 15]
+  -r, --retries <N>         Retries for failed requests [default: 2]
+      --abc on|off          Enable or disable Automatic Baseline Correction on start
+  -h, --help                Print this help
+
+The sensor can't report its ABC state, so winsen_abc_enabled is only exported
+when the state is set with --abc. Readings are not exported while the sensor
+fails to respond, winsen_last_success_timestamp_seconds tells how old the last
+one is.
+";
+
+struct Options {
+    port: String,
+    listen: String,
+    interval: Duration,
+    retries: u8,
+    abc: Option<bool>,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        port: "/dev/ttyUSB0".into(),
+        listen: "127.0.0.1:9942".into(),
+        interval: Duration::from_secs(15),
+        retries: 2,
+        abc: None,
+    };
+
+    let mut args = Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            },
+            "-p" | "--port" => options.port = args.value(&arg),
+            "-l" | "--listen" => options.listen = args.value(&arg),
+            "-i" | "--interval" => options.interval = args.duration(&arg),
+            "-r" | "--retries" => options.retries = args.value(&arg),
+            "--abc" => {
+                options.abc = match args.next().as_deref() {
+                    Some("on") => Some(true),
+                    Some("off") => Some(false),
+                    _ => args.error("--abc expects on or off"),
+                }
+            },
+            _ => args.error(&format!("unknown option: {}", arg)),
+        }
+    }
+    options
+}
+
+/// Error kinds exported even before they occur
+const ERROR_KINDS: [Error<()>; 4] = [Error::Timeout, Error::Serial(()), Error::WrongChecksum, Error::WrongPacketType];
+
+/// Latest sensor state and error counters
+struct Metrics {
+    measurement: Option<Measurement>,
+    last_success: Option<SystemTime>,
+    detection_range: Option<u32>,
+    abc: Option<bool>,
+    firmware: Option<String>,
+    samples: u64,
+    /// Failed requests by error kind
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            measurement: None,
+            last_success: None,
+            detection_range: None,
+            abc: None,
+            firmware: None,
+            samples: 0,
+            errors: ERROR_KINDS.iter().map(|e| (e.kind(), 0)).collect(),
+        }
+    }
+}
+
+impl Metrics {
+    fn count_error<E>(&mut self, error: &Error<E>) {
+        *self.errors.entry(error.kind()).or_default() += 1;
+    }
+
+    /// Render metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: Option<String>| {
+            if let Some(value) = value {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+            }
+        };
+
+        let measurement = self.measurement.as_ref();
+        gauge("winsen_co2_ppm", "CO2 concentration in ppm.", measurement.map(|m| m.co2_ppm.to_string()));
+        gauge("winsen_temperature_celsius", "Sensor module temperature.", measurement.map(|m| m.temperature_c.to_string()));
+        gauge("winsen_status", "Status byte of the last reading.", measurement.map(|m| m.status.to_string()));
+        gauge("winsen_detection_range_ppm", "Sensor detection range in ppm.", self.detection_range.map(|r| r.to_string()));
+        gauge("winsen_abc_enabled", "Automatic Baseline Correction state set on start.", self.abc.map(|abc| u8::from(abc).to_string()));
+        gauge(
+            "winsen_last_success_timestamp_seconds",
+            "Time of the last successful reading.",
+            self.last_success.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs().to_string()),
+        );
+
+        if let Some(firmware) = &self.firmware {
+            let _ = writeln!(out, "# HELP winsen_info Sensor information.\n# TYPE winsen_info gauge");
+            let _ = writeln!(out, "winsen_info{{firmware=\"{}\"}} 1", escape_label(firmware));
+        }
+
+        let _ = writeln!(out, "# HELP winsen_samples_total Successful readings.\n# TYPE winsen_samples_total counter");
+        let _ = writeln!(out, "winsen_samples_total {}", self.samples);
+        let _ = writeln!(out, "# HELP winsen_errors_total Failed requests by error kind.\n# TYPE winsen_errors_total counter");
+        for (kind, count) in &self.errors {
+            let _ = writeln!(out, "winsen_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn handle_connection(stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.lock().unwrap().render();
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", body)
+        },
+        (Some("GET"), Some("/")) => ("200 OK", "text/plain; charset=utf-8", "See /metrics\n".into()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", "Not found\n".into()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "Method not allowed\n".into()),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body,
+    )
+}
+
+fn serve(listener: TcpListener, metrics: Arc<Mutex<Metrics>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(stream, &metrics) {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        eprintln!("warning: HTTP connection failed: {}", e);
+                    }
+                }
+            },
+            Err(e) => eprintln!("warning: can't accept connection: {}", e),
+        }
+    }
+}
+
+fn main() {
+    let options = parse_options();
+
+    let serial = Serial::open(&options.port).unwrap_or_else(|e| {
+        eprintln!("error: can't open {}: {}", options.port, e);
+        exit(1);
+    });
+    let listener = TcpListener::bind(&options.listen).unwrap_or_else(|e| {
+        eprintln!("error: can't listen on {}: {}", options.listen, e);
+        exit(1);
+    });
+
+    let config = Config::default().retry(RetryPolicy::fixed(options.retries, 50));
+    let mut sensor = WinsenSensor::with_config(serial, Instant::now(), config);
+    match sensor.probe() {
+        Ok(true) => {},
+        Ok(false) => eprintln!("warning: sensor not detected on {}, will keep trying", options.port),
+        Err(e) => eprintln!("warning: probe failed: {:?}", e),
+    }
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    {
+        let metrics = metrics.clone();
+        spawn(move || serve(listener, metrics));
+    }
+
+    let mut abc_pending = options.abc;
+    loop {
+        let started = Instant::now();
+
+        // Static information is requested until it is known
+        if let Some(enabled) = abc_pending {
+            let result = sensor.set_automatic_baseline_correction(enabled);
+            let mut metrics = metrics.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    metrics.abc = Some(enabled);
+                    abc_pending = None;
+                },
+                Err(e) => metrics.count_error(&e),
+            }
+        }
+        if metrics.lock().unwrap().detection_range.is_none() {
+            let result = sensor.get_detection_range();
+            let mut metrics = metrics.lock().unwrap();
+            match result {
+                Ok(range) => metrics.detection_range = Some(range.ppm()),
+                Err(e) => metrics.count_error(&e),
+            }
+        }
+        if metrics.lock().unwrap().firmware.is_none() {
+            let result = sensor.get_firmware_version();
+            let mut metrics = metrics.lock().unwrap();
+            match result {
+                Ok(version) => metrics.firmware = Some(String::from_utf8_lossy(&version).into_owned()),
+                Err(e) => metrics.count_error(&e),
+            }
+        }
+
+        let result = sensor.read_measurement();
+        {
+            let mut metrics = metrics.lock().unwrap();
+            match result {
+                Ok(measurement) => {
+                    metrics.measurement = Some(measurement);
+                    metrics.last_success = Some(SystemTime::now());
+                    metrics.samples += 1;
+                },
+                Err(e) => {
+                    eprintln!("warning: reading failed: {}", e);
+                    // Don't let scrapes record the last reading as a current one
+                    metrics.measurement = None;
+                    metrics.count_error(&e);
+                },
+            }
+        }
+
+        sleep(options.interval.saturating_sub(started.elapsed()));
+    }
+}
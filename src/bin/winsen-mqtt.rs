@@ -0,0 +1,349 @@
+//! MQTT bridge with Home Assistant discovery.
+//!
+//! Publishes sensor readings as JSON and accepts commands for zero point calibration,
+//! Automatic Baseline Correction and the detection range.
+
+mod common;
+
+use std::process::exit;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use linux_embedded_hal::Serial;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use winsen_co2_sensor::{Concentration, Config, RetryPolicy, WinsenSensor};
+
+use crate::common::{json_string, Args};
+
+const USAGE: &str = "\
+Usage: winsen-mqtt [OPTIONS]
+
+Publishes sensor readings to an MQTT broker with Home Assistant discovery.
+
+Topics, relative to <PREFIX>/<ID>:
+  state                    Readings as JSON, e.g. {\"co2_ppm\":612,\"temperature_c\":24}
+  availability             online or offline
+  abc                      Automatic Baseline Correction state, ON or OFF
+  range                    Detection range in ppm
+  abc/set                  Command: ON or OFF
+  range/set                Command: detection range in ppm, 2000 or 5000
+  calibrate_zero           Command: perform zero point calibration, PRESS
+
+Retained commands are ignored.
+
+Options:
+  -p, --port <PATH>           Serial port [default: /dev/ttyUSB0]
+  -b, --broker <HOST:PORT>    MQTT broker [default: localhost:1883]
+  -u, --username <NAME>       MQTT user name
+  -P, --password <PASSWORD>   MQTT password
+      --id <ID>               Device identifier, letters, digits, _ and - [default: mhz19]
+      --prefix <PREFIX>       Topic prefix [default: winsen]
+      --discovery-prefix <P>  Home Assistant discovery prefix [default: homeassistant]
+  -i, --interval <SECONDS>    Publishing interval [default: 60]
+  -r, --retries <N>           Retries for failed requests [default: 2]
+      --abc on|off            Automatic Baseline Correction state set on start [default: on]
+  -h, --help                  Print this help
+
+The sensor can't report its ABC state, so it is set on start and published from
+then on. Pass --abc off to keep ABC disabled across restarts.
+";
+
+struct Options {
+    port: String,
+    host: String,
+    mqtt_port: u16,
+    credentials: Option<(String, String)>,
+    id: String,
+    prefix: String,
+    discovery_prefix: String,
+    interval: Duration,
+    retries: u8,
+    abc: bool,
+}
+
+fn parse_options() -> Options {
+    let mut port = "/dev/ttyUSB0".to_string();
+    let mut broker = "localhost:1883".to_string();
+    let mut username = None;
+    let mut password = None;
+    let mut id = "mhz19".to_string();
+    let mut prefix = "winsen".to_string();
+    let mut discovery_prefix = "homeassistant".to_string();
+    let mut interval = Duration::from_secs(60);
+    let mut retries = 2;
+    let mut abc = true;
+
+    let mut args = Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            },
+            "-p" | "--port" => port = args.value(&arg),
+            "-b" | "--broker" => broker = args.value(&arg),
+            "-u" | "--username" => username = Some(args.value(&arg)),
+            "-P" | "--password" => password = Some(args.value(&arg)),
+            "--id" => id = args.value(&arg),
+            "--prefix" => prefix = args.value(&arg),
+            "--discovery-prefix" => discovery_prefix = args.value(&arg),
+            "-i" | "--interval" => interval = args.duration(&arg),
+            "-r" | "--retries" => retries = args.value(&arg),
+            "--abc" => {
+                abc = match args.next().as_deref() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => args.error("--abc expects on or off"),
+                }
+            },
+            _ => args.error(&format!("unknown option: {}", arg)),
+        }
+    }
+
+    let (host, mqtt_port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (host.to_string(), args.parse("--broker", port)),
+        None => (broker, 1883),
+    };
+    // Home Assistant accepts only these characters in discovery object IDs
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        args.error("--id must only contain letters, digits, _ and -");
+    }
+    for (name, value) in [("--prefix", &prefix), ("--discovery-prefix", &discovery_prefix)] {
+        if value.is_empty() || value.contains(['+', '#']) {
+            args.error(&format!("{} must not be empty or contain MQTT wildcards", name));
+        }
+    }
+
+    Options {
+        port,
+        host,
+        mqtt_port,
+        credentials: username.map(|u| (u, password.unwrap_or_default())),
+        id,
+        prefix,
+        discovery_prefix,
+        interval,
+        retries,
+        abc,
+    }
+}
+
+/// Commands received from the broker
+enum Command {
+    CalibrateZero,
+    SetAbc(bool),
+    SetRange(u32),
+}
+
+struct Topics {
+    base: String,
+}
+
+impl Topics {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+
+    /// Topic quoted for discovery payloads
+    fn json(&self, name: &str) -> String {
+        json_string(&self.topic(name))
+    }
+}
+
+/// Home Assistant discovery messages as `(topic, payload)` pairs
+///
+/// The ID only contains characters which don't need escaping, topics are quoted.
+fn discovery(options: &Options, topics: &Topics) -> Vec<(String, String)> {
+    let id = &options.id;
+    let device = format!(
+        r#"{{"identifiers":["winsen_{}"],"name":"CO2 sensor {}","manufacturer":"Winsen","model":"MH-Z19"}}"#,
+        id, id,
+    );
+    let common = format!(
+        r#""availability_topic":{},"device":{}"#,
+        topics.json("availability"), device,
+    );
+    let config = |component: &str, object: &str| {
+        format!("{}/{}/winsen_{}/{}/config", options.discovery_prefix, component, id, object)
+    };
+
+    vec![
+        (config("sensor", "co2"), format!(
+            r#"{{"name":"CO2","unique_id":"winsen_{}_co2","device_class":"carbon_dioxide","state_class":"measurement","unit_of_measurement":"ppm","state_topic":{},"value_template":"{{{{ value_json.co2_ppm }}}}",{}}}"#,
+            id, topics.json("state"), common,
+        )),
+        (config("sensor", "temperature"), format!(
+            r#"{{"name":"Temperature","unique_id":"winsen_{}_temperature","device_class":"temperature","state_class":"measurement","unit_of_measurement":"°C","state_topic":{},"value_template":"{{{{ value_json.temperature_c }}}}","entity_category":"diagnostic",{}}}"#,
+            id, topics.json("state"), common,
+        )),
+        (config("switch", "abc"), format!(
+            r#"{{"name":"Automatic baseline correction","unique_id":"winsen_{}_abc","state_topic":{},"command_topic":{},"entity_category":"config",{}}}"#,
+            id, topics.json("abc"), topics.json("abc/set"), common,
+        )),
+        (config("select", "range"), format!(
+            r#"{{"name":"Detection range","unique_id":"winsen_{}_range","state_topic":{},"command_topic":{},"options":["2000","5000"],"entity_category":"config",{}}}"#,
+            id, topics.json("range"), topics.json("range/set"), common,
+        )),
+        (config("button", "calibrate_zero"), format!(
+            r#"{{"name":"Zero point calibration","unique_id":"winsen_{}_calibrate_zero","command_topic":{},"entity_category":"config",{}}}"#,
+            id, topics.json("calibrate_zero"), common,
+        )),
+    ]
+}
+
+fn abc_payload(enabled: bool) -> String {
+    if enabled { "ON" } else { "OFF" }.into()
+}
+
+/// Forward incoming commands to the sensor thread and keep the connection alive
+fn run_connection(mut connection: Connection, client: Client, topics: Topics, commands: Sender<Command>) {
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // Subscriptions are lost when the session is not persistent
+                for name in ["calibrate_zero", "abc/set", "range/set"] {
+                    if let Err(e) = client.subscribe(topics.topic(name), QoS::AtLeastOnce) {
+                        eprintln!("warning: can't subscribe: {}", e);
+                    }
+                }
+                let _ = client.publish(topics.topic("availability"), QoS::AtLeastOnce, true, "online");
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                let payload = payload.trim();
+                // A retained command would be redelivered on every resubscription
+                if publish.retain {
+                    eprintln!("warning: ignoring retained {} = {:?}", publish.topic, payload);
+                    continue;
+                }
+                let command = match publish.topic.strip_prefix(&topics.base).unwrap_or("") {
+                    "/calibrate_zero" if payload == "PRESS" => Some(Command::CalibrateZero),
+                    "/abc/set" => match payload {
+                        "ON" | "on" | "1" | "true" => Some(Command::SetAbc(true)),
+                        "OFF" | "off" | "0" | "false" => Some(Command::SetAbc(false)),
+                        _ => None,
+                    },
+                    "/range/set" => match payload {
+                        "2000" => Some(Command::SetRange(2000)),
+                        "5000" => Some(Command::SetRange(5000)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match command {
+                    Some(command) => {
+                        if commands.send(command).is_err() {
+                            return;
+                        }
+                    },
+                    None => eprintln!("warning: ignoring {} = {:?}", publish.topic, payload),
+                }
+            },
+            Ok(_) => {},
+            Err(e) => {
+                // The client reconnects on the next iteration
+                eprintln!("warning: MQTT connection error: {}", e);
+                std::thread::sleep(Duration::from_secs(5));
+            },
+        }
+    }
+}
+
+fn main() {
+    let options = parse_options();
+    let topics = Topics {
+        base: format!("{}/{}", options.prefix, options.id),
+    };
+
+    let serial = Serial::open(&options.port).unwrap_or_else(|e| {
+        eprintln!("error: can't open {}: {}", options.port, e);
+        exit(1);
+    });
+    let config = Config::default().retry(RetryPolicy::fixed(options.retries, 50));
+    let mut sensor = WinsenSensor::with_config(serial, Instant::now(), config);
+    match sensor.probe() {
+        Ok(true) => {},
+        Ok(false) => eprintln!("warning: sensor not detected on {}, will keep trying", options.port),
+        Err(e) => eprintln!("warning: probe failed: {:?}", e),
+    }
+
+    let mut mqtt_options = MqttOptions::new(format!("winsen-{}", options.id), options.host.clone(), options.mqtt_port);
+    mqtt_options.set_keep_alive(Duration::from_secs(30));
+    mqtt_options.set_last_will(LastWill::new(topics.topic("availability"), "offline", QoS::AtLeastOnce, true));
+    if let Some((username, password)) = &options.credentials {
+        mqtt_options.set_credentials(username.clone(), password.clone());
+    }
+    let (client, connection) = Client::new(mqtt_options, 16);
+
+    for (topic, payload) in discovery(&options, &topics) {
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload) {
+            eprintln!("warning: can't publish discovery config: {}", e);
+        }
+    }
+
+    let (commands_tx, commands) = channel();
+    {
+        let client = client.clone();
+        let topics = Topics {
+            base: topics.base.clone(),
+        };
+        spawn(move || run_connection(connection, client, topics, commands_tx));
+    }
+
+    let publish = |name: &str, payload: String| {
+        if let Err(e) = client.publish(topics.topic(name), QoS::AtLeastOnce, name != "state", payload) {
+            eprintln!("warning: can't publish {}: {}", name, e);
+        }
+    };
+
+    if let Ok(range) = sensor.get_detection_range() {
+        publish("range", range.ppm().to_string());
+    }
+    // Set until the sensor accepts it, a command from the broker takes precedence
+    let mut abc_pending = Some(options.abc);
+
+    let mut next_sample = Instant::now();
+    loop {
+        match commands.recv_timeout(next_sample.saturating_duration_since(Instant::now())) {
+            Ok(Command::CalibrateZero) => match sensor.calibrate_zero_point() {
+                Ok(()) => eprintln!("info: zero point calibration done"),
+                Err(e) => eprintln!("warning: zero point calibration failed: {}", e),
+            },
+            Ok(Command::SetAbc(enabled)) => {
+                abc_pending = None;
+                match sensor.set_automatic_baseline_correction(enabled) {
+                    Ok(()) => publish("abc", abc_payload(enabled)),
+                    Err(e) => eprintln!("warning: can't set ABC: {}", e),
+                }
+            },
+            Ok(Command::SetRange(range)) => match sensor.set_detection_range(Concentration::from_ppm(range)) {
+                Ok(()) => publish("range", range.to_string()),
+                Err(e) => eprintln!("warning: can't set detection range: {}", e),
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                next_sample += options.interval;
+                if let Some(enabled) = abc_pending {
+                    match sensor.set_automatic_baseline_correction(enabled) {
+                        Ok(()) => {
+                            publish("abc", abc_payload(enabled));
+                            abc_pending = None;
+                        },
+                        Err(e) => eprintln!("warning: can't set ABC: {}", e),
+                    }
+                }
+                match sensor.read_measurement() {
+                    Ok(m) => publish("state", format!(
+                        r#"{{"co2_ppm":{},"temperature_c":{},"status":{}}}"#,
+                        m.co2_ppm, m.temperature_c, m.status,
+                    )),
+                    Err(e) => eprintln!("warning: reading failed: {}", e),
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("error: MQTT connection thread stopped");
+                exit(1);
+            },
+        }
+    }
+}
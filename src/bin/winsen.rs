@@ -0,0 +1,280 @@
+//! Command-line tool for sensor management.
+
+mod common;
+
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::process::exit;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use linux_embedded_hal::Serial;
+use winsen_co2_sensor::{Concentration, Config, Error, Measurement, RetryPolicy, WinsenSensor};
+
+use crate::common::{json_string, Args};
+
+const USAGE: &str = "\
+Usage: winsen [OPTIONS] <COMMAND>
+
+Commands:
+  probe                    Check that a sensor is connected
+  read [--watch] [--interval <SECONDS>]
+                           Read CO2 concentration, temperature and status
+  abc on|off               Enable or disable Automatic Baseline Correction
+  calibrate zero           Perform zero point calibration (400 ppm)
+  calibrate span <PPM>     Perform span point calibration
+  range get                Get the detection range
+  range set <PPM>          Set the detection range
+  analog-bounds            Get CO2 concentration bounds of the analog output
+  firmware                 Get the firmware version
+  reset                    Reset the sensor MCU
+
+Options:
+  -p, --port <PATH>        Serial port [default: /dev/ttyUSB0]
+  -r, --retries <N>        Retries for failed requests [default: 2]
+  -j, --json               Print JSON instead of human-readable output
+  -h, --help               Print this help
+
+Exit codes:
+  0  success
+  1  sensor not detected
+  2  usage error
+  3  serial port error
+  4  request timed out
+  5  wrong checksum
+  6  wrong packet type
+";
+
+const EXIT_NOT_DETECTED: i32 = 1;
+const EXIT_SERIAL: i32 = 3;
+const EXIT_TIMEOUT: i32 = 4;
+const EXIT_WRONG_CHECKSUM: i32 = 5;
+const EXIT_WRONG_PACKET_TYPE: i32 = 6;
+
+enum Command {
+    Probe,
+    Read {
+        watch: bool,
+        interval: Duration,
+    },
+    Abc(bool),
+    CalibrateZero,
+    CalibrateSpan(u16),
+    RangeGet,
+    RangeSet(u32),
+    AnalogBounds,
+    Firmware,
+    Reset,
+}
+
+struct Options {
+    port: String,
+    retries: u8,
+    json: bool,
+    command: Command,
+}
+
+fn parse_options() -> Options {
+    let mut port = "/dev/ttyUSB0".to_string();
+    let mut retries = 2;
+    let mut json = false;
+    let mut watch = false;
+    let mut interval = Duration::from_secs(5);
+    let mut words = Vec::new();
+
+    let mut args = Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            },
+            "-p" | "--port" => port = args.value(&arg),
+            "-r" | "--retries" => retries = args.value(&arg),
+            "-j" | "--json" => json = true,
+            "--watch" => watch = true,
+            "--interval" => interval = args.duration(&arg),
+            _ if arg.starts_with('-') => args.error(&format!("unknown option: {}", arg)),
+            _ => words.push(arg),
+        }
+    }
+
+    let words: Vec<&str> = words.iter().map(|s| s.as_str()).collect();
+    let command = match words.as_slice() {
+        ["probe"] => Command::Probe,
+        ["read"] => Command::Read { watch, interval },
+        ["abc", "on"] => Command::Abc(true),
+        ["abc", "off"] => Command::Abc(false),
+        ["calibrate", "zero"] => Command::CalibrateZero,
+        ["calibrate", "span", ppm] => Command::CalibrateSpan(args.parse("span", ppm)),
+        ["range", "get"] => Command::RangeGet,
+        ["range", "set", ppm] => Command::RangeSet(args.parse("range", ppm)),
+        ["analog-bounds"] => Command::AnalogBounds,
+        ["firmware"] => Command::Firmware,
+        ["reset"] => Command::Reset,
+        [] => args.error("missing command"),
+        _ => args.error(&format!("unknown command: {}", words.join(" "))),
+    };
+    if watch && !matches!(command, Command::Read { .. }) {
+        args.error("--watch is only supported by the read command");
+    }
+
+    Options {
+        port,
+        retries,
+        json,
+        command,
+    }
+}
+
+fn exit_code<E>(error: &Error<E>) -> i32 {
+    match error {
+        Error::Timeout => EXIT_TIMEOUT,
+        Error::Serial(_) => EXIT_SERIAL,
+        Error::WrongChecksum => EXIT_WRONG_CHECKSUM,
+        Error::WrongPacketType => EXIT_WRONG_PACKET_TYPE,
+    }
+}
+
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    /// Print a result given as JSON object fields and as a human-readable line
+    fn print(&self, fields: &[(&str, String)], human: &str) {
+        if self.json {
+            let fields: Vec<String> = fields.iter().map(|(k, v)| format!("{}:{}", json_string(k), v)).collect();
+            println!("{{{}}}", fields.join(","));
+        } else {
+            println!("{}", human);
+        }
+    }
+
+    fn ok(&self, human: &str) {
+        self.print(&[("ok", "true".into())], human);
+    }
+
+    fn error<E: Debug>(&self, error: &Error<E>) {
+        if self.json {
+            let message = json_string(&error.to_string());
+            println!("{{\"error\":{},\"message\":{}}}", json_string(error.kind()), message);
+        } else {
+            eprintln!("error: {}", error);
+        }
+    }
+
+    fn measurement(&self, measurement: &Measurement) {
+        self.print(&[
+            ("co2_ppm", measurement.co2_ppm.to_string()),
+            ("temperature_c", measurement.temperature_c.to_string()),
+            ("status", measurement.status.to_string()),
+        ], &format!(
+            "CO2: {} ppm, temperature: {} °C, status: 0x{:02x}",
+            measurement.co2_ppm, measurement.temperature_c, measurement.status,
+        ));
+    }
+}
+
+fn run(sensor: &mut WinsenSensor<Serial, Instant>, command: &Command, output: &Output) -> Result<(), Error<ErrorKind>> {
+    match *command {
+        // Handled before any other command
+        Command::Probe => unreachable!(),
+        Command::Read { watch: false, .. } => output.measurement(&sensor.read_measurement()?),
+        Command::Read { watch: true, interval } => loop {
+            let started = Instant::now();
+            match sensor.read_measurement() {
+                Ok(measurement) => output.measurement(&measurement),
+                // Keep watching, the next request may succeed
+                Err(e) => output.error(&e),
+            }
+            sleep(interval.saturating_sub(started.elapsed()));
+        },
+        Command::Abc(enabled) => {
+            sensor.set_automatic_baseline_correction(enabled)?;
+            output.ok(if enabled { "ABC enabled" } else { "ABC disabled" });
+        },
+        Command::CalibrateZero => {
+            sensor.calibrate_zero_point()?;
+            output.ok("Zero point calibration done");
+        },
+        Command::CalibrateSpan(span) => {
+            sensor.calibrate_span_point(span.into())?;
+            output.ok(&format!("Span point calibration at {} ppm done", span));
+        },
+        Command::RangeGet => {
+            let range = sensor.get_detection_range()?.ppm();
+            output.print(&[("range_ppm", range.to_string())], &format!("Detection range: {} ppm", range));
+        },
+        Command::RangeSet(range) => {
+            sensor.set_detection_range(Concentration::from_ppm(range))?;
+            output.ok(&format!("Detection range set to {} ppm", range));
+        },
+        Command::AnalogBounds => {
+            let (high, low) = sensor.get_analog_bounds()?;
+            let (high, low) = (high.ppm(), low.ppm());
+            output.print(
+                &[("high_ppm", high.to_string()), ("low_ppm", low.to_string())],
+                &format!("Analog output bounds: {} - {} ppm", low, high),
+            );
+        },
+        Command::Firmware => {
+            let version = sensor.get_firmware_version()?;
+            let version = String::from_utf8_lossy(&version);
+            output.print(&[("firmware", json_string(&version))], &format!("Firmware version: {}", version));
+        },
+        Command::Reset => {
+            sensor.reset()?;
+            output.ok("Reset requested");
+        },
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options();
+    let output = Output {
+        json: options.json,
+    };
+
+    let serial = match Serial::open(&options.port) {
+        Ok(serial) => serial,
+        Err(e) => {
+            if output.json {
+                println!("{{\"error\":\"serial\",\"message\":{}}}", json_string(&e.to_string()));
+            } else {
+                eprintln!("error: can't open {}: {}", options.port, e);
+            }
+            exit(EXIT_SERIAL);
+        },
+    };
+    let config = Config::default().retry(RetryPolicy::fixed(options.retries, 50));
+    let mut sensor = WinsenSensor::with_config(serial, Instant::now(), config);
+
+    // Probing skips the bootloader prompt, so it's done before every command
+    let detected = match sensor.probe() {
+        Ok(detected) => detected,
+        Err(e) => {
+            let e = Error::Serial(e);
+            output.error(&e);
+            exit(exit_code(&e));
+        },
+    };
+    if let Command::Probe = options.command {
+        output.print(&[("detected", detected.to_string())], if detected { "Sensor detected" } else { "Sensor not detected" });
+        exit(if detected { 0 } else { EXIT_NOT_DETECTED });
+    }
+    if !detected {
+        if output.json {
+            println!("{{\"error\":\"not_detected\",\"message\":\"sensor not detected\"}}");
+        } else {
+            eprintln!("error: sensor not detected");
+        }
+        exit(EXIT_NOT_DETECTED);
+    }
+
+    if let Err(e) = run(&mut sensor, &options.command, &output) {
+        output.error(&e);
+        exit(exit_code(&e));
+    }
+}
@@ -0,0 +1,585 @@
+//! Guided calibration.
+//!
+//! Calibration commands are executed blindly by the sensor, calibrating in unstable
+//! conditions ruins its accuracy. [`Calibrator`] samples the sensor before sending a
+//! command, refuses to calibrate if the readings aren't stable and verifies the result.
+//!
+//! Calibrations take minutes. The waits between readings are done by a `delay` closure
+//! called with the time in milliseconds, e.g. `|ms| std::thread::sleep(Duration::from_millis(ms.into()))`
+//! or `|ms| delay.delay_ms(ms)` with an `embedded-hal` delay, so that the CPU can sleep.
+
+use core::fmt;
+
+use crate::{checked_ticks, elapsed_ms, Concentration, Error, MonotonicCounter, Serial, WinsenSensor};
+
+/// Statistics of readings taken during a sampling window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowStats {
+    pub min: Concentration,
+    pub max: Concentration,
+    /// Mean rounded to the nearest ppm
+    pub mean: Concentration,
+    pub count: u32,
+}
+
+impl WindowStats {
+    /// Difference between the highest and the lowest reading in ppm
+    pub fn spread(&self) -> u16 {
+        self.max.ppm_u16() - self.min.ppm_u16()
+    }
+
+    /// Whether the mean is within `tolerance` ppm of `target`
+    pub fn is_near(&self, target: Concentration, tolerance: u16) -> bool {
+        self.mean.ppm().abs_diff(target.ppm()) <= u32::from(tolerance)
+    }
+}
+
+/// Zero point calibration parameters
+///
+/// Before calibrating, `samples` readings are taken `interval_ms` apart. They must not spread
+/// by more than `max_spread_ppm` and their mean must be within `tolerance_ppm` of
+/// `baseline`. After calibrating and waiting `settle_ms`, the same number of readings
+/// must be within `verify_tolerance_ppm` of the baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZeroCalibration {
+    /// Concentration the sensor is exposed to, 400 ppm for fresh outdoor air
+    pub baseline: Concentration,
+    pub samples: u32,
+    pub interval_ms: u32,
+    pub max_spread_ppm: u16,
+    pub tolerance_ppm: u16,
+    pub settle_ms: u32,
+    pub verify_tolerance_ppm: u16,
+}
+
+impl Default for ZeroCalibration {
+    fn default() -> Self {
+        ZeroCalibration {
+            baseline: Concentration::from_ppm(400),
+            samples: 30,
+            interval_ms: 10_000,
+            max_spread_ppm: 30,
+            tolerance_ppm: 150,
+            settle_ms: 10_000,
+            verify_tolerance_ppm: 30,
+        }
+    }
+}
+
+impl ZeroCalibration {
+    /// Set the concentration the sensor is exposed to
+    pub fn baseline(mut self, baseline: Concentration) -> Self {
+        self.baseline = baseline;
+        self
+    }
+
+    /// Set the number of readings taken before and after calibrating, and the time between them
+    pub fn sampling(mut self, samples: u32, interval_ms: u32) -> Self {
+        self.samples = samples;
+        self.interval_ms = interval_ms;
+        self
+    }
+
+    /// Set the maximum spread of readings considered stable
+    pub fn max_spread_ppm(mut self, ppm: u16) -> Self {
+        self.max_spread_ppm = ppm;
+        self
+    }
+
+    /// Set the maximum deviation of the mean from the baseline before calibrating
+    pub fn tolerance_ppm(mut self, ppm: u16) -> Self {
+        self.tolerance_ppm = ppm;
+        self
+    }
+
+    /// Set the time between the calibration command and the verification
+    pub fn settle_ms(mut self, ms: u32) -> Self {
+        self.settle_ms = ms;
+        self
+    }
+
+    /// Set the maximum deviation of the mean from the baseline after calibrating
+    pub fn verify_tolerance_ppm(mut self, ppm: u16) -> Self {
+        self.verify_tolerance_ppm = ppm;
+        self
+    }
+}
+
+/// Span point calibration parameters
+///
+/// The sensor must be exposed to a `reference` gas, which becomes the span.
+/// The span must be at least 1000 ppm and within `detection_range`, and a zero point
+/// calibration must have been done by the [`Calibrator`] within `max_zero_age_ms`.
+///
+/// Windows of `samples` readings taken `interval_ms` apart are sampled until their spread
+/// is within `max_spread_ppm`, for at most `stabilization_timeout_ms`. The mean of the
+/// stable window must be within `tolerance_ppm` of the reference. After calibrating and
+/// waiting `settle_ms`, readings must be within `verify_tolerance_ppm` of the reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanCalibration {
+    /// Concentration of the reference gas
+    pub reference: Concentration,
+    /// Detection range the sensor is configured for
+    pub detection_range: Concentration,
+    pub max_zero_age_ms: u32,
+    pub samples: u32,
+    pub interval_ms: u32,
+    pub max_spread_ppm: u16,
+    pub stabilization_timeout_ms: u32,
+    pub tolerance_ppm: u16,
+    pub settle_ms: u32,
+    pub verify_tolerance_ppm: u16,
+}
+
+/// Lowest span point accepted by the sensor
+pub const MIN_SPAN: Concentration = Concentration::from_ppm(1000);
+
+impl SpanCalibration {
+    pub fn new(reference: Concentration) -> Self {
+        SpanCalibration {
+            reference,
+            detection_range: Concentration::from_ppm(5000),
+            max_zero_age_ms: 3_600_000,
+            samples: 10,
+            interval_ms: 10_000,
+            max_spread_ppm: 50,
+            stabilization_timeout_ms: 1_200_000,
+            tolerance_ppm: 300,
+            settle_ms: 10_000,
+            verify_tolerance_ppm: 50,
+        }
+    }
+
+    /// Set the detection range the sensor is configured for
+    pub fn detection_range(mut self, range: Concentration) -> Self {
+        self.detection_range = range;
+        self
+    }
+
+    /// Set the maximum time since the last zero point calibration
+    pub fn max_zero_age_ms(mut self, ms: u32) -> Self {
+        self.max_zero_age_ms = ms;
+        self
+    }
+
+    /// Set the number of readings in a window, and the time between them
+    pub fn sampling(mut self, samples: u32, interval_ms: u32) -> Self {
+        self.samples = samples;
+        self.interval_ms = interval_ms;
+        self
+    }
+
+    /// Set the maximum spread of readings considered stable
+    pub fn max_spread_ppm(mut self, ppm: u16) -> Self {
+        self.max_spread_ppm = ppm;
+        self
+    }
+
+    /// Set the maximum time to wait for stable readings
+    pub fn stabilization_timeout_ms(mut self, ms: u32) -> Self {
+        self.stabilization_timeout_ms = ms;
+        self
+    }
+
+    /// Set the maximum deviation of the mean from the reference before calibrating
+    pub fn tolerance_ppm(mut self, ppm: u16) -> Self {
+        self.tolerance_ppm = ppm;
+        self
+    }
+
+    /// Set the time between the calibration command and the verification
+    pub fn settle_ms(mut self, ms: u32) -> Self {
+        self.settle_ms = ms;
+        self
+    }
+
+    /// Set the maximum deviation of the mean from the reference after calibrating
+    pub fn verify_tolerance_ppm(mut self, ppm: u16) -> Self {
+        self.verify_tolerance_ppm = ppm;
+        self
+    }
+}
+
+/// Result of a calibration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CalibrationReport {
+    /// Concentration the sensor was calibrated to
+    pub target: Concentration,
+    /// Readings before calibrating
+    pub before: WindowStats,
+    /// Readings after calibrating
+    pub after: WindowStats,
+}
+
+impl CalibrationReport {
+    /// Difference between the mean reading and the target after calibrating
+    pub fn residual_error(&self) -> i32 {
+        i32::from(self.after.mean.ppm_u16()) - i32::from(self.target.ppm_u16())
+    }
+}
+
+/// Calibration failure
+#[derive(Debug, PartialEq)]
+pub enum CalibrationError<E> {
+    /// Communication with the sensor failed
+    Sensor(Error<E>),
+    /// Readings spread too much, nothing was sent to the sensor
+    Unstable(WindowStats),
+    /// Readings are too far from the expected concentration, nothing was sent to the sensor
+    OutOfTolerance(WindowStats),
+    /// Span is below 1000 ppm or above the detection range
+    InvalidSpan(Concentration),
+    /// No zero point calibration was done recently
+    ZeroCalibrationRequired,
+    /// The sensor was calibrated, but readings don't match the expected concentration
+    NotConverged(CalibrationReport),
+}
+
+impl<E> From<Error<E>> for CalibrationError<E> {
+    fn from(e: Error<E>) -> Self {
+        CalibrationError::Sensor(e)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for CalibrationError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::Sensor(e) => write!(f, "{}", e),
+            CalibrationError::Unstable(stats) => {
+                write!(f, "readings are not stable ({} - {} ppm)", stats.min.ppm(), stats.max.ppm())
+            },
+            CalibrationError::OutOfTolerance(stats) => {
+                write!(f, "readings are out of tolerance (mean {})", stats.mean)
+            },
+            CalibrationError::InvalidSpan(span) => write!(f, "invalid span point {}", span),
+            CalibrationError::ZeroCalibrationRequired => f.write_str("zero point calibration required"),
+            CalibrationError::NotConverged(report) => {
+                write!(f, "readings did not converge after calibration (mean {})", report.after.mean)
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug> std::error::Error for CalibrationError<E> {}
+
+/// [`WinsenSensor`] wrapper performing verified calibrations
+pub struct Calibrator<S, C> {
+    sensor: WinsenSensor<S, C>,
+    /// Counter value of the last successful zero point calibration
+    last_zero_calibration: Option<u32>,
+    /// Counter value of the last successful span point calibration
+    last_span_calibration: Option<u32>,
+}
+
+impl<S: Serial, C: MonotonicCounter> Calibrator<S, C> {
+    pub fn new(sensor: WinsenSensor<S, C>) -> Self {
+        Calibrator {
+            sensor,
+            last_zero_calibration: None,
+            last_span_calibration: None,
+        }
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut WinsenSensor<S, C> {
+        &mut self.sensor
+    }
+
+    pub fn free(self) -> WinsenSensor<S, C> {
+        self.sensor
+    }
+
+    /// Record a zero point calibration done `ms` milliseconds ago by other means
+    ///
+    /// A timestamp `at` kept in application defined units of `ms_per_unit` milliseconds,
+    /// e.g. RTC seconds, maps to `ms = (now - at) * ms_per_unit`. Calibrations further back
+    /// than the counter range, e.g. 71 minutes at 1 MHz, are forgotten as too old.
+    pub fn set_zero_calibrated_ms_ago(&mut self, ms: u32) {
+        self.last_zero_calibration = self.counter_value_ms_ago(ms);
+    }
+
+    /// Returns milliseconds elapsed since the last zero point calibration
+    pub fn zero_calibration_age_ms(&self) -> Option<u32> {
+        self.last_zero_calibration.map(|t| elapsed_ms(&self.sensor.counter, t))
+    }
+
+    /// Record a span point calibration done `ms` milliseconds ago by other means
+    ///
+    /// Calibrations further back than the counter range are forgotten.
+    pub fn set_span_calibrated_ms_ago(&mut self, ms: u32) {
+        self.last_span_calibration = self.counter_value_ms_ago(ms);
+    }
+
+    /// Returns milliseconds elapsed since the last span point calibration
+    pub fn span_calibration_age_ms(&self) -> Option<u32> {
+        self.last_span_calibration.map(|t| elapsed_ms(&self.sensor.counter, t))
+    }
+
+    /// Counter value `ms` milliseconds ago, `None` if that's beyond the counter range
+    fn counter_value_ms_ago(&self, ms: u32) -> Option<u32> {
+        let ticks = checked_ticks(&self.sensor.counter, ms)?;
+        Some(self.sensor.counter.value().wrapping_sub(ticks))
+    }
+
+    /// Take `samples` readings `interval_ms` apart
+    fn sample(&mut self, samples: u32, interval_ms: u32, delay: &mut impl FnMut(u32)) -> Result<WindowStats, Error<S::Error>> {
+        let mut stats = WindowStats {
+            min: Concentration::from_ppm(u32::MAX),
+            max: Concentration::from_ppm(0),
+            mean: Concentration::from_ppm(0),
+            count: 0,
+        };
+        let mut sum = 0u64;
+        for i in 0..samples.max(1) {
+            if i > 0 {
+                delay(interval_ms);
+            }
+            let concentration = self.sensor.read_co2_concentration()?;
+            stats.min = stats.min.min(concentration);
+            stats.max = stats.max.max(concentration);
+            sum += u64::from(concentration.ppm());
+            stats.count += 1;
+        }
+        let count = u64::from(stats.count);
+        stats.mean = Concentration::from_ppm(((sum + count / 2) / count) as u32);
+        Ok(stats)
+    }
+
+    /// Perform a zero point calibration if readings are stable near the baseline
+    ///
+    /// The sensor must be in a stable environment with the baseline concentration,
+    /// e.g. outdoors, for at least 20 minutes before calling this. Blocks for about
+    /// `2 * samples * interval_ms + settle_ms`, waiting with `delay`.
+    pub fn calibrate_zero_point(
+        &mut self,
+        params: &ZeroCalibration,
+        mut delay: impl FnMut(u32),
+    ) -> Result<CalibrationReport, CalibrationError<S::Error>> {
+        let before = self.sample(params.samples, params.interval_ms, &mut delay)?;
+        if before.spread() > params.max_spread_ppm {
+            return Err(CalibrationError::Unstable(before));
+        }
+        if !before.is_near(params.baseline, params.tolerance_ppm) {
+            return Err(CalibrationError::OutOfTolerance(before));
+        }
+
+        self.sensor.calibrate_zero_point()?;
+        self.last_zero_calibration = Some(self.sensor.counter.value());
+        delay(params.settle_ms);
+
+        let after = self.sample(params.samples, params.interval_ms, &mut delay)?;
+        let report = CalibrationReport {
+            target: params.baseline,
+            before,
+            after,
+        };
+        if after.spread() > params.max_spread_ppm || !after.is_near(params.baseline, params.verify_tolerance_ppm) {
+            return Err(CalibrationError::NotConverged(report));
+        }
+        Ok(report)
+    }
+
+    /// Perform a span point calibration once readings are stable with the reference gas
+    ///
+    /// Blocks until readings are stable, for at most `stabilization_timeout_ms`, then for
+    /// about `samples * interval_ms + settle_ms` more, waiting with `delay`.
+    pub fn calibrate_span_point(
+        &mut self,
+        params: &SpanCalibration,
+        mut delay: impl FnMut(u32),
+    ) -> Result<CalibrationReport, CalibrationError<S::Error>> {
+        let span = params.reference;
+        if span < MIN_SPAN || span > params.detection_range {
+            return Err(CalibrationError::InvalidSpan(span));
+        }
+        match self.zero_calibration_age_ms() {
+            Some(age) if age <= params.max_zero_age_ms => {},
+            _ => return Err(CalibrationError::ZeroCalibrationRequired),
+        }
+
+        let started = self.sensor.counter.value();
+        let before = loop {
+            let stats = self.sample(params.samples, params.interval_ms, &mut delay)?;
+            if stats.spread() <= params.max_spread_ppm {
+                break stats;
+            }
+            if elapsed_ms(&self.sensor.counter, started) >= params.stabilization_timeout_ms {
+                return Err(CalibrationError::Unstable(stats));
+            }
+        };
+        if !before.is_near(span, params.tolerance_ppm) {
+            return Err(CalibrationError::OutOfTolerance(before));
+        }
+
+        self.sensor.calibrate_span_point(span)?;
+        self.last_span_calibration = Some(self.sensor.counter.value());
+        delay(params.settle_ms);
+
+        let after = self.sample(params.samples, params.interval_ms, &mut delay)?;
+        let report = CalibrationReport {
+            target: span,
+            before,
+            after,
+        };
+        if after.spread() > params.max_spread_ppm || !after.is_near(span, params.verify_tolerance_ppm) {
+            return Err(CalibrationError::NotConverged(report));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+
+    fn calibrator(curve: Co2Curve) -> Calibrator<SimulatedSensor, StepCounter> {
+        Calibrator::new(WinsenSensor::new(SimulatedSensor::new(curve), StepCounter::default()))
+    }
+
+    /// Delay counting its calls and the total time waited
+    struct Delays {
+        calls: u32,
+        total_ms: u32,
+    }
+
+    impl Delays {
+        fn new() -> Self {
+            Delays {
+                calls: 0,
+                total_ms: 0,
+            }
+        }
+
+        fn delay(&mut self) -> impl FnMut(u32) + '_ {
+            move |ms| {
+                self.calls += 1;
+                self.total_ms += ms;
+            }
+        }
+    }
+
+    fn zero_params() -> ZeroCalibration {
+        ZeroCalibration::default().sampling(4, 1000).settle_ms(5000)
+    }
+
+    #[test]
+    fn unstable_readings_prevent_zero_calibration() {
+        let mut calibrator = calibrator(Co2Curve::Sequence(&[400, 500]));
+        let mut delays = Delays::new();
+        match calibrator.calibrate_zero_point(&zero_params(), delays.delay()) {
+            Err(CalibrationError::Unstable(stats)) => assert_eq!(stats.spread(), 100),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(calibrator.sensor_mut().serial.zero_calibrations(), 0);
+        assert_eq!(calibrator.zero_calibration_age_ms(), None);
+        assert_eq!(delays.calls, 3);
+        assert_eq!(delays.total_ms, 3000);
+    }
+
+    #[test]
+    fn readings_far_from_the_baseline_prevent_zero_calibration() {
+        let mut calibrator = calibrator(Co2Curve::Constant(800));
+        match calibrator.calibrate_zero_point(&zero_params(), Delays::new().delay()) {
+            Err(CalibrationError::OutOfTolerance(stats)) => assert_eq!(stats.mean, Concentration::from_ppm(800)),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(calibrator.sensor_mut().serial.zero_calibrations(), 0);
+    }
+
+    #[test]
+    fn zero_calibration() {
+        let mut calibrator = calibrator(Co2Curve::Constant(450));
+        let mut delays = Delays::new();
+        let report = calibrator.calibrate_zero_point(&zero_params(), delays.delay()).unwrap();
+        assert_eq!(report.target, Concentration::from_ppm(400));
+        assert_eq!(report.before.mean, Concentration::from_ppm(450));
+        assert_eq!(report.after.mean, Concentration::from_ppm(400));
+        assert_eq!(report.residual_error(), 0);
+        assert_eq!(calibrator.sensor_mut().serial.zero_calibrations(), 1);
+        assert!(calibrator.zero_calibration_age_ms().is_some());
+        // Sampling before and after, and settling in between
+        assert_eq!(delays.calls, 3 + 1 + 3);
+        assert_eq!(delays.total_ms, 3000 + 5000 + 3000);
+    }
+
+    #[test]
+    fn zero_calibration_not_converging() {
+        // The concentration rises right after calibrating
+        let mut calibrator = calibrator(Co2Curve::Sequence(&[450, 450, 450, 450, 450, 600, 600, 600]));
+        match calibrator.calibrate_zero_point(&zero_params(), Delays::new().delay()) {
+            Err(CalibrationError::NotConverged(report)) => {
+                assert_eq!(report.before.mean, Concentration::from_ppm(450));
+                assert_eq!(report.after.max, Concentration::from_ppm(550));
+            },
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(calibrator.sensor_mut().serial.zero_calibrations(), 1);
+    }
+
+    fn span_params(ppm: u32) -> SpanCalibration {
+        SpanCalibration::new(Concentration::from_ppm(ppm)).sampling(3, 1000).settle_ms(5000)
+    }
+
+    #[test]
+    fn span_outside_of_the_valid_range() {
+        let mut calibrator = calibrator(Co2Curve::Constant(1900));
+        calibrator.set_zero_calibrated_ms_ago(0);
+        let below = Concentration::from_ppm(MIN_SPAN.ppm() - 1);
+        assert_eq!(
+            calibrator.calibrate_span_point(&SpanCalibration::new(below), Delays::new().delay()),
+            Err(CalibrationError::InvalidSpan(below)),
+        );
+        let above = Concentration::from_ppm(2001);
+        let params = SpanCalibration::new(above).detection_range(Concentration::from_ppm(2000));
+        assert_eq!(
+            calibrator.calibrate_span_point(&params, Delays::new().delay()),
+            Err(CalibrationError::InvalidSpan(above)),
+        );
+        assert_eq!(calibrator.sensor_mut().serial.span_calibrations(), 0);
+    }
+
+    #[test]
+    fn span_calibration_requires_a_recent_zero_calibration() {
+        let mut calibrator = calibrator(Co2Curve::Constant(1900));
+        assert_eq!(
+            calibrator.calibrate_span_point(&span_params(2000), Delays::new().delay()),
+            Err(CalibrationError::ZeroCalibrationRequired),
+        );
+
+        calibrator.set_zero_calibrated_ms_ago(2 * 3_600_000);
+        assert_eq!(
+            calibrator.calibrate_span_point(&span_params(2000), Delays::new().delay()),
+            Err(CalibrationError::ZeroCalibrationRequired),
+        );
+        assert_eq!(calibrator.sensor_mut().serial.span_calibrations(), 0);
+    }
+
+    #[test]
+    fn span_calibration_gives_up_on_unstable_readings() {
+        let mut calibrator = calibrator(Co2Curve::Sequence(&[1900, 2100]));
+        calibrator.set_zero_calibrated_ms_ago(0);
+        let params = span_params(2000).stabilization_timeout_ms(50);
+        match calibrator.calibrate_span_point(&params, Delays::new().delay()) {
+            Err(CalibrationError::Unstable(stats)) => assert_eq!(stats.spread(), 200),
+            result => panic!("unexpected {:?}", result),
+        }
+        // Several windows were sampled before giving up
+        assert!(calibrator.sensor_mut().serial.samples() > 3);
+        assert_eq!(calibrator.sensor_mut().serial.span_calibrations(), 0);
+    }
+
+    #[test]
+    fn span_calibration() {
+        let mut calibrator = calibrator(Co2Curve::Constant(1900));
+        calibrator.set_zero_calibrated_ms_ago(0);
+        let mut delays = Delays::new();
+        let report = calibrator.calibrate_span_point(&span_params(2000), delays.delay()).unwrap();
+        assert_eq!(report.target, Concentration::from_ppm(2000));
+        assert_eq!(report.before.mean, Concentration::from_ppm(1900));
+        assert_eq!(report.after.mean, Concentration::from_ppm(2000));
+        assert_eq!(calibrator.sensor_mut().serial.span_calibrations(), 1);
+        assert!(calibrator.span_calibration_age_ms().is_some());
+        assert_eq!(delays.calls, 2 + 1 + 2);
+    }
+}
@@ -0,0 +1,161 @@
+//! Barometric pressure and temperature compensation.
+//!
+//! NDIR sensors measure the number of CO2 molecules in the optical path, which scales
+//! with air density. Readings are corrected to the reference conditions using the ideal
+//! gas law: `corrected = raw * (p_ref / p) * (t / t_ref)`, temperatures in kelvin.
+
+use crate::{Co2Sensor, Concentration, Measurement};
+
+/// Standard sea level pressure in hPa
+pub const STANDARD_PRESSURE_HPA: f32 = 1013.25;
+
+/// Temperature the sensor readings are assumed to be calibrated at, in °C
+pub const REFERENCE_TEMPERATURE_C: f32 = 25.0;
+
+const ZERO_CELSIUS_K: f32 = 273.15;
+
+/// Pressure at `altitude_m` meters above sea level in the standard atmosphere, in hPa
+pub fn pressure_at_altitude(altitude_m: f32) -> f32 {
+    STANDARD_PRESSURE_HPA * libm::powf(1.0 - 2.255_77e-5 * altitude_m, 5.255_88)
+}
+
+/// Ambient conditions and the reference conditions readings are corrected to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compensation {
+    /// Ambient pressure in hPa
+    pub pressure_hpa: f32,
+    /// Ambient temperature in °C, `None` to compensate pressure only
+    pub temperature_c: Option<f32>,
+    pub reference_pressure_hpa: f32,
+    pub reference_temperature_c: f32,
+}
+
+impl Default for Compensation {
+    fn default() -> Self {
+        Compensation {
+            pressure_hpa: STANDARD_PRESSURE_HPA,
+            temperature_c: None,
+            reference_pressure_hpa: STANDARD_PRESSURE_HPA,
+            reference_temperature_c: REFERENCE_TEMPERATURE_C,
+        }
+    }
+}
+
+impl Compensation {
+    /// Compensation for a measured ambient pressure
+    pub fn with_pressure_hpa(pressure_hpa: f32) -> Self {
+        Compensation {
+            pressure_hpa,
+            ..Default::default()
+        }
+    }
+
+    /// Compensation for a site without a barometer, at a fixed altitude
+    pub fn with_altitude_m(altitude_m: f32) -> Self {
+        Self::with_pressure_hpa(pressure_at_altitude(altitude_m))
+    }
+
+    /// Set the ambient temperature
+    pub fn temperature_c(mut self, temperature_c: Option<f32>) -> Self {
+        self.temperature_c = temperature_c;
+        self
+    }
+
+    /// Set the conditions the sensor was calibrated at
+    pub fn reference(mut self, pressure_hpa: f32, temperature_c: f32) -> Self {
+        self.reference_pressure_hpa = pressure_hpa;
+        self.reference_temperature_c = temperature_c;
+        self
+    }
+
+    /// Returns the factor readings are multiplied by
+    pub fn factor(&self) -> f32 {
+        let pressure = self.reference_pressure_hpa / self.pressure_hpa;
+        let temperature = match self.temperature_c {
+            Some(t) => (t + ZERO_CELSIUS_K) / (self.reference_temperature_c + ZERO_CELSIUS_K),
+            None => 1.0,
+        };
+        pressure * temperature
+    }
+
+    /// Compensate a reading
+    pub fn apply(&self, concentration: Concentration) -> Concentration {
+        Concentration::from_ppm_f32(concentration.ppm() as f32 * self.factor())
+    }
+}
+
+/// Wrapper compensating CO2 readings of a [`WinsenSensor`](crate::WinsenSensor) or another
+/// [`Co2Sensor`] for pressure and temperature
+pub struct CompensatedSensor<T> {
+    sensor: T,
+    compensation: Compensation,
+    sensor_temperature: bool,
+}
+
+impl<T> CompensatedSensor<T> {
+    pub fn new(sensor: T, compensation: Compensation) -> Self {
+        CompensatedSensor {
+            sensor,
+            compensation,
+            sensor_temperature: false,
+        }
+    }
+
+    /// Compensate temperature with the module temperature reported with each reading
+    ///
+    /// The module temperature is a few degrees above the ambient one.
+    pub fn use_sensor_temperature(mut self, enabled: bool) -> Self {
+        self.sensor_temperature = enabled;
+        self
+    }
+
+    pub fn compensation(&self) -> &Compensation {
+        &self.compensation
+    }
+
+    pub fn set_compensation(&mut self, compensation: Compensation) {
+        self.compensation = compensation;
+    }
+
+    /// Update the ambient pressure, e.g. from a barometer
+    pub fn set_pressure_hpa(&mut self, pressure_hpa: f32) {
+        self.compensation.pressure_hpa = pressure_hpa;
+    }
+
+    /// Update the ambient temperature
+    ///
+    /// Ignored if the sensor temperature is used.
+    pub fn set_temperature_c(&mut self, temperature_c: Option<f32>) {
+        self.compensation.temperature_c = temperature_c;
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut T {
+        &mut self.sensor
+    }
+
+    pub fn free(self) -> T {
+        self.sensor
+    }
+}
+
+impl<T: Co2Sensor> Co2Sensor for CompensatedSensor<T> {
+    type Error = T::Error;
+
+    fn read_measurement(&mut self) -> Result<Measurement, T::Error> {
+        let mut measurement = self.sensor.read_measurement()?;
+        let mut compensation = self.compensation;
+        if self.sensor_temperature {
+            compensation.temperature_c = Some(f32::from(measurement.temperature_c));
+        }
+        measurement.co2_ppm = compensation.apply(measurement.co2()).ppm_u16();
+        Ok(measurement)
+    }
+
+    fn read_co2_concentration(&mut self) -> Result<Concentration, T::Error> {
+        if self.sensor_temperature {
+            return self.read_measurement().map(|m| m.co2());
+        }
+        let concentration = self.sensor.read_co2_concentration()?;
+        Ok(self.compensation.apply(concentration))
+    }
+}
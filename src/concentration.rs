@@ -0,0 +1,119 @@
+//! Typed gas concentration.
+
+use core::fmt;
+
+/// Molar mass of CO2 in g/mol
+const CO2_MOLAR_MASS: f32 = 44.01;
+
+/// Molar gas constant in J/(mol K)
+const GAS_CONSTANT: f32 = 8.314_462;
+
+const ZERO_CELSIUS_K: f32 = 273.15;
+
+/// CO2 concentration, stored as a volume fraction in ppm
+///
+/// Mass concentration depends on the air density, so conversions to and from mg/m³
+/// take the temperature and the pressure of the air.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Concentration(u32);
+
+impl Concentration {
+    pub const fn from_ppm(ppm: u32) -> Self {
+        Concentration(ppm)
+    }
+
+    /// Concentration rounded to the nearest ppm, negative values and NaN become 0
+    pub fn from_ppm_f32(ppm: f32) -> Self {
+        // Float to integer casts saturate, NaN becomes 0
+        Concentration((ppm + 0.5) as u32)
+    }
+
+    /// Concentration in percent volume, rounded to the nearest ppm
+    pub fn from_percent(percent: f32) -> Self {
+        Self::from_ppm_f32(percent * 10_000.0)
+    }
+
+    /// Concentration in mg/m³ at `temperature_c` and `pressure_hpa`, rounded to the nearest ppm
+    pub fn from_mg_per_m3(mg_per_m3: f32, temperature_c: f32, pressure_hpa: f32) -> Self {
+        Self::from_ppm_f32(mg_per_m3 / mg_per_m3_per_ppm(temperature_c, pressure_hpa))
+    }
+
+    pub const fn ppm(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the concentration in percent volume
+    pub fn percent(self) -> f32 {
+        self.0 as f32 / 10_000.0
+    }
+
+    /// Returns the concentration in mg/m³ at `temperature_c` and `pressure_hpa`
+    pub fn mg_per_m3(self, temperature_c: f32, pressure_hpa: f32) -> f32 {
+        self.0 as f32 * mg_per_m3_per_ppm(temperature_c, pressure_hpa)
+    }
+
+    /// Returns the concentration in ppm as sent over the wire, saturated to `u16`
+    pub fn ppm_u16(self) -> u16 {
+        self.0.min(u32::from(u16::MAX)) as u16
+    }
+}
+
+/// Mass concentration of 1 ppm: `M * p / (R * T)`, with pressure converted from hPa
+/// and the result from g/m³ to mg/m³ cancelling out
+fn mg_per_m3_per_ppm(temperature_c: f32, pressure_hpa: f32) -> f32 {
+    CO2_MOLAR_MASS * pressure_hpa / (GAS_CONSTANT * (temperature_c + ZERO_CELSIUS_K)) / 10.0
+}
+
+impl From<u16> for Concentration {
+    fn from(ppm: u16) -> Self {
+        Concentration(u32::from(ppm))
+    }
+}
+
+impl fmt::Display for Concentration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ppm", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mg_per_m3_at_standard_conditions() {
+        // 1 ppm is about 1.8 mg/m³ at 25 °C and 1013.25 hPa
+        let mg = Concentration::from_ppm(1000).mg_per_m3(25.0, 1013.25);
+        assert!((mg - 1799.0).abs() < 1.0, "{}", mg);
+        // Denser air holds more CO2 per m³
+        assert!(Concentration::from_ppm(1000).mg_per_m3(0.0, 1013.25) > mg);
+        assert!(Concentration::from_ppm(1000).mg_per_m3(25.0, 800.0) < mg);
+    }
+
+    #[test]
+    fn round_trips() {
+        for &ppm in &[0, 1, 400, 1234, 5000, 10_000, 50_000] {
+            let c = Concentration::from_ppm(ppm);
+            assert_eq!(Concentration::from_percent(c.percent()), c);
+            assert_eq!(Concentration::from_mg_per_m3(c.mg_per_m3(21.5, 980.0), 21.5, 980.0), c);
+            assert_eq!(Concentration::from(c.ppm_u16()), c);
+        }
+    }
+
+    #[test]
+    fn from_ppm_f32_rounds_and_saturates() {
+        assert_eq!(Concentration::from_ppm_f32(399.5), Concentration::from_ppm(400));
+        assert_eq!(Concentration::from_ppm_f32(399.4), Concentration::from_ppm(399));
+        assert_eq!(Concentration::from_ppm_f32(-20.0), Concentration::from_ppm(0));
+        assert_eq!(Concentration::from_ppm_f32(f32::NAN), Concentration::from_ppm(0));
+        assert_eq!(Concentration::from_ppm_f32(1e12), Concentration::from_ppm(u32::MAX));
+        assert_eq!(Concentration::from_ppm_f32(f32::INFINITY), Concentration::from_ppm(u32::MAX));
+    }
+
+    #[test]
+    fn ppm_u16_saturates() {
+        assert_eq!(Concentration::from_ppm(65_535).ppm_u16(), u16::MAX);
+        assert_eq!(Concentration::from_ppm(70_000).ppm_u16(), u16::MAX);
+        assert_eq!(Concentration::from_ppm(800).ppm_u16(), 800);
+    }
+}
@@ -0,0 +1,183 @@
+//! Timeouts and retry policy shared by all drivers.
+
+use crate::Error;
+
+/// Driver configuration
+///
+/// All timeouts are in milliseconds. The default configuration matches the sensor
+/// timing at 9600 baud and doesn't retry failed requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Maximum time to transmit a request
+    pub send_timeout_ms: u32,
+    /// Maximum time between the request and the first byte of the response
+    pub response_timeout_ms: u32,
+    /// Maximum time between two consecutive bytes of the response
+    pub inter_byte_timeout_ms: u32,
+    /// Discard received bytes before sending each request
+    pub flush_rx: bool,
+    /// Retry policy applied to every command
+    pub retry: RetryPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            send_timeout_ms: 100,
+            response_timeout_ms: 100,
+            inter_byte_timeout_ms: 50,
+            flush_rx: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Set the request transmission timeout
+    pub fn send_timeout_ms(mut self, ms: u32) -> Self {
+        self.send_timeout_ms = ms;
+        self
+    }
+
+    /// Set the timeout for the first byte of the response
+    pub fn response_timeout_ms(mut self, ms: u32) -> Self {
+        self.response_timeout_ms = ms;
+        self
+    }
+
+    /// Set the timeout between two consecutive bytes of the response
+    pub fn inter_byte_timeout_ms(mut self, ms: u32) -> Self {
+        self.inter_byte_timeout_ms = ms;
+        self
+    }
+
+    /// Enable or disable discarding of stale received bytes before each request
+    pub fn flush_rx(mut self, enabled: bool) -> Self {
+        self.flush_rx = enabled;
+        self
+    }
+
+    /// Set the retry policy
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+/// Maximum number of bytes discarded while flushing RX or looking for a response
+pub(crate) const DISCARD_LIMIT: usize = 256;
+
+/// Delay between retries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same time before every retry
+    Fixed(u32),
+    /// Double the delay after every retry, starting at `initial_ms` and never exceeding `max_ms`
+    Exponential {
+        initial_ms: u32,
+        max_ms: u32,
+    },
+}
+
+/// Errors that cause a request to be retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryOn {
+    /// Retry on [`Error::Timeout`]
+    pub timeout: bool,
+    /// Retry on [`Error::Serial`]
+    pub serial: bool,
+    /// Retry on [`Error::WrongChecksum`]
+    pub wrong_checksum: bool,
+    /// Retry on [`Error::WrongPacketType`]
+    pub wrong_packet_type: bool,
+}
+
+impl Default for RetryOn {
+    /// Retry on all protocol errors, but not on serial port errors
+    fn default() -> Self {
+        RetryOn {
+            timeout: true,
+            serial: false,
+            wrong_checksum: true,
+            wrong_packet_type: true,
+        }
+    }
+}
+
+/// Retry policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u8,
+    /// Delay between attempts
+    pub backoff: Backoff,
+    /// Errors that are worth retrying
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            backoff: Backoff::Fixed(0),
+            retry_on: RetryOn::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retry `retries` times with a fixed delay of `backoff_ms`
+    pub fn fixed(retries: u8, backoff_ms: u32) -> Self {
+        RetryPolicy {
+            retries,
+            backoff: Backoff::Fixed(backoff_ms),
+            retry_on: RetryOn::default(),
+        }
+    }
+
+    /// Retry `retries` times with an exponentially growing delay
+    pub fn exponential(retries: u8, initial_ms: u32, max_ms: u32) -> Self {
+        RetryPolicy {
+            retries,
+            backoff: Backoff::Exponential {
+                initial_ms,
+                max_ms,
+            },
+            retry_on: RetryOn::default(),
+        }
+    }
+
+    /// Policy used by `probe`: MH-Z19B requires two retries to skip the bootloader prompt,
+    /// and first packets can be corrupted, so protocol errors are always retried
+    pub(crate) fn for_probe(self) -> Self {
+        RetryPolicy {
+            retries: self.retries.max(2),
+            retry_on: RetryOn {
+                serial: self.retry_on.serial,
+                ..RetryOn::default()
+            },
+            ..self
+        }
+    }
+
+    /// Returns `true` if a request that failed with `error` should be retried
+    pub fn is_retryable<E>(&self, error: &Error<E>) -> bool {
+        match error {
+            Error::Timeout => self.retry_on.timeout,
+            Error::Serial(_) => self.retry_on.serial,
+            Error::WrongChecksum => self.retry_on.wrong_checksum,
+            Error::WrongPacketType => self.retry_on.wrong_packet_type,
+        }
+    }
+
+    /// Returns the delay before retry number `retry` (starting from 0)
+    pub fn backoff_ms(&self, retry: u8) -> u32 {
+        match self.backoff {
+            Backoff::Fixed(ms) => ms,
+            Backoff::Exponential { initial_ms, max_ms } => {
+                let factor = 1u32.checked_shl(retry.into()).unwrap_or(u32::MAX);
+                initial_ms.saturating_mul(factor).min(max_ms)
+            },
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Software correction of readings.
+//!
+//! Sensors co-located with a reference instrument can be corrected with a linear
+//! [`Correction`] fitted from paired samples by [`fit`], optionally followed by a
+//! [`PiecewiseLinear`] curve for non-linear deviations.
+
+use crate::{Co2Sensor, Concentration, Measurement};
+
+/// Linear correction `corrected = gain * raw + offset`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Correction {
+    pub offset: f32,
+    pub gain: f32,
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Correction {
+    /// Correction leaving readings unchanged
+    pub const IDENTITY: Correction = Correction {
+        offset: 0.0,
+        gain: 1.0,
+    };
+
+    pub const fn new(offset: f32, gain: f32) -> Self {
+        Correction {
+            offset,
+            gain,
+        }
+    }
+
+    pub fn apply(&self, ppm: f32) -> f32 {
+        self.gain * ppm + self.offset
+    }
+}
+
+/// Piecewise-linear mapping through `(input, output)` points sorted by input
+///
+/// Values outside of the points are extrapolated from the first or the last segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PiecewiseLinear<'a> {
+    points: &'a [(f32, f32)],
+}
+
+impl<'a> PiecewiseLinear<'a> {
+    /// Returns `None` if there are less than two points or inputs aren't strictly increasing
+    pub fn new(points: &'a [(f32, f32)]) -> Option<Self> {
+        if points.len() < 2 || points.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return None;
+        }
+        Some(PiecewiseLinear {
+            points,
+        })
+    }
+
+    pub fn points(&self) -> &'a [(f32, f32)] {
+        self.points
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        // Index of the segment end, the first or the last segment outside of the points
+        let end = self.points.iter()
+            .position(|&(input, _)| input > value)
+            .unwrap_or(self.points.len() - 1)
+            .max(1);
+        let (x0, y0) = self.points[end - 1];
+        let (x1, y1) = self.points[end];
+        y0 + (value - x0) * (y1 - y0) / (x1 - x0)
+    }
+}
+
+/// Least squares fit of a linear correction from `(sensor, reference)` sample pairs,
+/// reference values in ppm
+///
+/// Returns `None` if there are less than two samples or all sensor readings are equal.
+pub fn fit(samples: &[(Concentration, f32)]) -> Option<Correction> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let n = samples.len() as f32;
+    let mean_x = samples.iter().map(|&(x, _)| x.ppm() as f32).sum::<f32>() / n;
+    let mean_y = samples.iter().map(|&(_, y)| y).sum::<f32>() / n;
+
+    // Centered sums keep the precision of f32 at typical ppm values
+    let mut sxx = 0.0;
+    let mut sxy = 0.0;
+    for &(x, y) in samples {
+        let dx = x.ppm() as f32 - mean_x;
+        sxx += dx * dx;
+        sxy += dx * (y - mean_y);
+    }
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let gain = sxy / sxx;
+    Some(Correction {
+        offset: mean_y - gain * mean_x,
+        gain,
+    })
+}
+
+/// Wrapper correcting CO2 readings of a [`WinsenSensor`](crate::WinsenSensor) or another
+/// [`Co2Sensor`]
+pub struct CorrectedSensor<'a, T> {
+    sensor: T,
+    correction: Correction,
+    curve: Option<PiecewiseLinear<'a>>,
+}
+
+impl<'a, T> CorrectedSensor<'a, T> {
+    pub fn new(sensor: T, correction: Correction) -> Self {
+        CorrectedSensor {
+            sensor,
+            correction,
+            curve: None,
+        }
+    }
+
+    /// Apply `curve` after the linear correction
+    pub fn with_curve(mut self, curve: PiecewiseLinear<'a>) -> Self {
+        self.curve = Some(curve);
+        self
+    }
+
+    pub fn correction(&self) -> Correction {
+        self.correction
+    }
+
+    pub fn set_correction(&mut self, correction: Correction) {
+        self.correction = correction;
+    }
+
+    pub fn set_curve(&mut self, curve: Option<PiecewiseLinear<'a>>) {
+        self.curve = curve;
+    }
+
+    /// Access the sensor, e.g. to read uncorrected values
+    pub fn sensor_mut(&mut self) -> &mut T {
+        &mut self.sensor
+    }
+
+    pub fn free(self) -> T {
+        self.sensor
+    }
+
+    /// Correct a raw reading
+    pub fn correct(&self, concentration: Concentration) -> Concentration {
+        let value = self.correction.apply(concentration.ppm() as f32);
+        Concentration::from_ppm_f32(match &self.curve {
+            Some(curve) => curve.apply(value),
+            None => value,
+        })
+    }
+}
+
+impl<'a, T: Co2Sensor> Co2Sensor for CorrectedSensor<'a, T> {
+    type Error = T::Error;
+
+    fn read_measurement(&mut self) -> Result<Measurement, T::Error> {
+        let mut measurement = self.sensor.read_measurement()?;
+        measurement.co2_ppm = self.correct(measurement.co2()).ppm_u16();
+        Ok(measurement)
+    }
+
+    fn read_co2_concentration(&mut self) -> Result<Concentration, T::Error> {
+        let concentration = self.sensor.read_co2_concentration()?;
+        Ok(self.correct(concentration))
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::compensation::{CompensatedSensor, Compensation, STANDARD_PRESSURE_HPA};
+    use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+    use crate::WinsenSensor;
+
+    #[test]
+    fn corrects_compensated_readings() {
+        let sensor = WinsenSensor::new(SimulatedSensor::new(Co2Curve::Constant(800)), StepCounter::default());
+        let compensation = Compensation::with_pressure_hpa(STANDARD_PRESSURE_HPA / 2.0);
+        let mut sensor = CorrectedSensor::new(CompensatedSensor::new(sensor, compensation), Correction::new(-100.0, 1.0));
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(1500)));
+        assert_eq!(sensor.read_measurement().map(|m| m.co2_ppm), Ok(1500));
+        assert_eq!(sensor.sensor_mut().sensor_mut().read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+    }
+}
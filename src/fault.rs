@@ -0,0 +1,422 @@
+//! Fault-injecting serial port wrapper for resilience testing.
+//!
+//! [`FaultySerial`] wraps any `embedded-hal` serial port and drops, duplicates, corrupts
+//! or delays bytes with configurable probabilities. The faults are driven by a seeded
+//! pseudo-random generator, so every run with the same seed is identical.
+
+use embedded_hal::serial::{Read, Write};
+
+/// Fault probabilities for one direction, each in the range `0.0..=1.0`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Probability of a byte being lost
+    pub drop: f32,
+    /// Probability of a byte being transferred twice
+    pub duplicate: f32,
+    /// Probability of a random bit flip in a byte
+    pub corrupt: f32,
+    /// Probability of a byte being delayed
+    pub delay: f32,
+    /// Number of polls a delayed byte is held back for
+    pub delay_polls: u32,
+}
+
+impl Faults {
+    /// No faults
+    pub fn none() -> Self {
+        Faults::default()
+    }
+}
+
+/// Number of injected faults
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    pub dropped: u32,
+    pub duplicated: u32,
+    pub corrupted: u32,
+    pub delayed: u32,
+}
+
+/// Xorshift32 generator
+#[derive(Clone, Debug)]
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // Zero is a fixed point of xorshift
+        Rng(if seed == 0 { 0x9e37_79b9 } else { seed })
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        // Compare 24 random bits to keep the conversion exact
+        probability > 0.0 && ((self.next_u32() >> 8) as f32) < probability * (1u32 << 24) as f32
+    }
+
+    fn corrupt(&mut self, byte: u8) -> u8 {
+        byte ^ (1 << (self.next_u32() % 8))
+    }
+}
+
+/// Serial port wrapper injecting faults in both directions
+pub struct FaultySerial<S> {
+    inner: S,
+    rng: Rng,
+    rx: Faults,
+    tx: Faults,
+    stats: FaultStats,
+
+    /// Byte held back by a delay or waiting to be duplicated
+    rx_pending: Option<u8>,
+    rx_delay: u32,
+    /// Remaining polls of the current transmit delay
+    tx_delay: Option<u32>,
+    /// Byte the faults were already injected into, waiting for the port to accept it
+    tx_pending: Option<u8>,
+}
+
+impl<S> FaultySerial<S> {
+    /// Wrap `inner`, injecting `rx` faults into received bytes and `tx` faults into transmitted ones
+    pub fn new(inner: S, rx: Faults, tx: Faults, seed: u32) -> Self {
+        FaultySerial {
+            inner,
+            rng: Rng::new(seed),
+            rx,
+            tx,
+            stats: FaultStats::default(),
+            rx_pending: None,
+            rx_delay: 0,
+            tx_delay: None,
+            tx_pending: None,
+        }
+    }
+
+    /// Change fault probabilities
+    pub fn set_faults(&mut self, rx: Faults, tx: Faults) {
+        self.rx = rx;
+        self.tx = tx;
+    }
+
+    /// Returns the number of faults injected so far
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<E, S: Read<u8, Error=E>> Read<u8> for FaultySerial<S> {
+    type Error = E;
+
+    fn read(&mut self) -> nb::Result<u8, E> {
+        if let Some(byte) = self.rx_pending {
+            if self.rx_delay > 0 {
+                self.rx_delay -= 1;
+                return Err(nb::Error::WouldBlock);
+            }
+            self.rx_pending = None;
+            return Ok(byte);
+        }
+
+        let mut byte = self.inner.read()?;
+        if self.rng.chance(self.rx.drop) {
+            self.stats.dropped += 1;
+            return Err(nb::Error::WouldBlock);
+        }
+        if self.rng.chance(self.rx.corrupt) {
+            self.stats.corrupted += 1;
+            byte = self.rng.corrupt(byte);
+        }
+        if self.rx.delay_polls > 0 && self.rng.chance(self.rx.delay) {
+            self.stats.delayed += 1;
+            self.rx_pending = Some(byte);
+            // This poll is the first one
+            self.rx_delay = self.rx.delay_polls - 1;
+            return Err(nb::Error::WouldBlock);
+        }
+        if self.rng.chance(self.rx.duplicate) {
+            self.stats.duplicated += 1;
+            self.rx_pending = Some(byte);
+        }
+        Ok(byte)
+    }
+}
+
+impl<E, S: Write<u8, Error=E>> Write<u8> for FaultySerial<S> {
+    type Error = E;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), E> {
+        // A byte the port was busy for is sent again, its faults are already decided
+        let byte = match self.tx_pending {
+            Some(byte) => byte,
+            None => {
+                match self.tx_delay {
+                    // The delay is over, transfer the byte
+                    Some(0) => self.tx_delay = None,
+                    Some(n) => {
+                        self.tx_delay = Some(n - 1);
+                        return Err(nb::Error::WouldBlock);
+                    },
+                    None => {
+                        if self.tx.delay_polls > 0 && self.rng.chance(self.tx.delay) {
+                            self.stats.delayed += 1;
+                            // This poll is the first one
+                            self.tx_delay = Some(self.tx.delay_polls - 1);
+                            return Err(nb::Error::WouldBlock);
+                        }
+                    },
+                }
+
+                if self.rng.chance(self.tx.drop) {
+                    self.stats.dropped += 1;
+                    return Ok(());
+                }
+                if self.rng.chance(self.tx.corrupt) {
+                    self.stats.corrupted += 1;
+                    self.rng.corrupt(byte)
+                } else {
+                    byte
+                }
+            },
+        };
+
+        match self.inner.write(byte) {
+            Ok(()) => self.tx_pending = None,
+            Err(nb::Error::WouldBlock) => {
+                self.tx_pending = Some(byte);
+                return Err(nb::Error::WouldBlock);
+            },
+            Err(e) => {
+                self.tx_pending = None;
+                return Err(e);
+            },
+        }
+        if self.rng.chance(self.tx.duplicate) {
+            self.stats.duplicated += 1;
+            // The duplicate is lost if the port is busy
+            if let Err(nb::Error::Other(e)) = self.inner.write(byte) {
+                return Err(nb::Error::Other(e));
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), E> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    /// Port accepting every other write
+    struct BusyPort {
+        busy: bool,
+        written: Option<u8>,
+    }
+
+    impl Write<u8> for BusyPort {
+        type Error = Infallible;
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            self.busy = !self.busy;
+            if !self.busy {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.written = Some(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn busy_port_gets_the_same_faulty_byte() {
+        let port = BusyPort {
+            busy: true,
+            written: None,
+        };
+        let tx = Faults {
+            corrupt: 1.0,
+            ..Faults::none()
+        };
+        let mut serial = FaultySerial::new(port, Faults::none(), tx, 1);
+        assert_eq!(serial.write(0x86), Err(nb::Error::WouldBlock));
+        assert_eq!(serial.write(0x86), Ok(()));
+        let written = serial.inner().written.unwrap();
+        assert_eq!((written ^ 0x86).count_ones(), 1);
+        assert_eq!(serial.stats().corrupted, 1);
+    }
+
+    #[cfg(feature = "sim")]
+    mod driver {
+        use super::*;
+        use crate::config::{Config, RetryPolicy};
+        use crate::protocol::Request;
+        use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+        use crate::{Concentration, Error, WinsenSensor};
+
+        fn sensor(rx: Faults, tx: Faults, seed: u32, retries: u8) -> WinsenSensor<FaultySerial<SimulatedSensor>, StepCounter> {
+            let serial = FaultySerial::new(SimulatedSensor::new(Co2Curve::Constant(800)), rx, tx, seed);
+            let config = Config::default().retry(RetryPolicy::fixed(retries, 10));
+            WinsenSensor::with_config(serial, StepCounter::default(), config)
+        }
+
+        fn noise() -> Faults {
+            Faults {
+                drop: 0.005,
+                duplicate: 0.005,
+                corrupt: 0.005,
+                delay: 0.005,
+                delay_polls: 20,
+            }
+        }
+
+        /// Queue the response to another command, as if an earlier answer arrived late
+        fn queue_stale_response(sensor: &mut WinsenSensor<FaultySerial<SimulatedSensor>, StepCounter>) {
+            for b in Request::GetDetectionRange.encode().as_bytes() {
+                sensor.serial.inner_mut().write(*b).unwrap();
+            }
+        }
+
+        #[test]
+        fn stale_response_is_skipped() {
+            let mut sensor = sensor(Faults::none(), Faults::none(), 1, 0);
+            queue_stale_response(&mut sensor);
+            assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+            assert_eq!(sensor.serial.inner().pending(), 0);
+        }
+
+        #[test]
+        fn stale_response_alone_is_wrong_packet_type() {
+            let tx = Faults {
+                drop: 1.0,
+                ..Faults::none()
+            };
+            let mut sensor = sensor(Faults::none(), tx, 1, 0);
+            queue_stale_response(&mut sensor);
+            assert_eq!(sensor.read_co2_concentration(), Err(Error::WrongPacketType));
+            assert_eq!(sensor.serial.inner().samples(), 0);
+
+            // The stale response was consumed, the next request gets its own answer
+            sensor.serial.set_faults(Faults::none(), Faults::none());
+            assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+        }
+
+        #[test]
+        fn retries_recover_from_noise() {
+            let mut sensor = sensor(noise(), noise(), 7, 5);
+            for _ in 0..100 {
+                assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+            }
+            let stats = sensor.serial.stats();
+            assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.corrupted > 0 && stats.delayed > 0);
+            // Some requests needed a retry
+            assert!(sensor.serial.inner().samples() > 100);
+        }
+
+        #[test]
+        fn noise_without_retries_fails() {
+            let mut sensor = sensor(noise(), noise(), 7, 0);
+            let failures = (0..100).filter(|_| sensor.read_co2_concentration().is_err()).count();
+            assert!(failures > 0);
+        }
+
+        #[test]
+        fn probe_skips_bootloader_prompt_under_noise() {
+            let mut sensor = sensor(noise(), noise(), 3, 2);
+            sensor.serial.inner_mut().set_bootloader_frames(2);
+            assert_eq!(sensor.probe(), Ok(true));
+        }
+
+        #[test]
+        fn probe_fails_without_responses() {
+            let rx = Faults {
+                drop: 1.0,
+                ..Faults::none()
+            };
+            let mut sensor = sensor(rx, Faults::none(), 1, 0);
+            assert_eq!(sensor.probe(), Ok(false));
+        }
+
+        #[test]
+        fn probe_retries_do_not_multiply() {
+            let rx = Faults {
+                drop: 1.0,
+                ..Faults::none()
+            };
+            // The bootloader retries count against the configured ones
+            let mut default_retries = sensor(rx, Faults::none(), 1, 2);
+            assert_eq!(default_retries.probe(), Ok(false));
+            assert_eq!(default_retries.serial.inner().samples(), 3);
+
+            let mut more_retries = sensor(rx, Faults::none(), 1, 4);
+            assert_eq!(more_retries.probe(), Ok(false));
+            assert_eq!(more_retries.serial.inner().samples(), 5);
+        }
+
+        #[test]
+        fn lost_responses_time_out_after_retries() {
+            let rx = Faults {
+                drop: 1.0,
+                ..Faults::none()
+            };
+            let mut sensor = sensor(rx, Faults::none(), 1, 3);
+            assert_eq!(sensor.read_co2_concentration(), Err(Error::Timeout));
+            assert_eq!(sensor.serial.inner().samples(), 4);
+        }
+
+        #[test]
+        fn corrupted_responses_fail_checksum() {
+            let rx = Faults {
+                corrupt: 0.02,
+                ..Faults::none()
+            };
+            let mut sensor = sensor(rx, Faults::none(), 5, 0);
+            let mut wrong_checksums = 0;
+            for _ in 0..100 {
+                match sensor.read_co2_concentration() {
+                    Ok(concentration) => assert_eq!(concentration, Concentration::from_ppm(800)),
+                    Err(Error::WrongChecksum) => wrong_checksums += 1,
+                    // The start byte was corrupted
+                    Err(Error::Timeout) => {},
+                    Err(e) => panic!("unexpected error {:?}", e),
+                }
+            }
+            assert!(wrong_checksums > 0);
+        }
+
+        #[test]
+        fn corrupted_responses_are_retried() {
+            let rx = Faults {
+                corrupt: 0.02,
+                ..Faults::none()
+            };
+            let mut sensor = sensor(rx, Faults::none(), 5, 3);
+            for _ in 0..100 {
+                assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+            }
+        }
+    }
+}
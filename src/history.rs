@@ -0,0 +1,417 @@
+//! Fixed-size reading history with rolling statistics.
+//!
+//! [`History`] keeps the last `N` readings and [`Tier`] the last `N` aggregated intervals,
+//! e.g. one tier of 1-minute and one of 1-hour buckets. Neither allocates, timestamps are
+//! [`MonotonicCounter`] values.
+
+use crate::{ticks, Concentration, MonotonicCounter};
+
+/// Ring buffer overwriting the oldest item when full
+#[derive(Clone, Debug)]
+struct Ring<T, const N: usize> {
+    items: [T; N],
+    /// Index of the oldest item
+    start: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    const fn new(fill: T) -> Self {
+        Ring {
+            items: [fill; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        if N == 0 {
+            return;
+        }
+        if self.len < N {
+            self.items[(self.start + self.len) % N] = item;
+            self.len += 1;
+        } else {
+            self.items[self.start] = item;
+            self.start = (self.start + 1) % N;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+
+    /// Iterate from the oldest item to the newest one
+    fn iter(&self) -> impl DoubleEndedIterator<Item=T> + ExactSizeIterator + '_ {
+        (0..self.len).map(move |i| self.items[(self.start + i) % N])
+    }
+}
+
+/// Which readings statistics are computed over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Window {
+    /// All stored readings
+    All,
+    /// The last `n` readings
+    Last(usize),
+    /// Readings not older than the number of milliseconds
+    Millis(u32),
+}
+
+/// Recorded CO2 concentration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Counter value when the reading was recorded
+    pub timestamp: u32,
+    pub concentration: Concentration,
+}
+
+/// Statistics over a window of readings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub min: Concentration,
+    pub max: Concentration,
+    /// Mean rounded to the nearest ppm
+    pub mean: Concentration,
+    /// Median, the mean of the two middle values for an even count
+    pub median: Concentration,
+    /// Number of readings in the window
+    pub count: usize,
+}
+
+/// Last `N` readings
+#[derive(Clone, Debug)]
+pub struct History<const N: usize> {
+    entries: Ring<Entry, N>,
+}
+
+impl<const N: usize> Default for History<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> History<N> {
+    pub const fn new() -> Self {
+        History {
+            entries: Ring::new(Entry { timestamp: 0, concentration: Concentration::from_ppm(0) }),
+        }
+    }
+
+    /// Add a reading with an explicit timestamp, dropping the oldest one if full
+    pub fn push(&mut self, timestamp: u32, concentration: Concentration) {
+        self.entries.push(Entry { timestamp, concentration });
+    }
+
+    /// Add a reading timestamped with the current counter value
+    pub fn record<C: MonotonicCounter>(&mut self, counter: &C, concentration: Concentration) {
+        self.push(counter.value(), concentration);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the most recent reading
+    pub fn latest(&self) -> Option<Entry> {
+        self.entries.iter().next_back()
+    }
+
+    /// Iterate from the oldest reading to the newest one
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=Entry> + '_ {
+        self.entries.iter()
+    }
+
+    /// Compute statistics over a window, `None` if the window is empty
+    ///
+    /// `counter` provides the current time for [`Window::Millis`].
+    pub fn stats<C: MonotonicCounter>(&self, counter: &C, window: Window) -> Option<Stats> {
+        let now = counter.value();
+        let max_age = match window {
+            Window::Millis(ms) => ticks(counter, ms),
+            _ => u32::MAX,
+        };
+        let skip = match window {
+            Window::Last(n) => self.len().saturating_sub(n),
+            _ => 0,
+        };
+
+        let mut values = [0u32; N];
+        let mut count = 0;
+        for entry in self.entries.iter().skip(skip) {
+            if now.wrapping_sub(entry.timestamp) <= max_age {
+                values[count] = entry.concentration.ppm();
+                count += 1;
+            }
+        }
+        let values = &mut values[..count];
+        if values.is_empty() {
+            return None;
+        }
+
+        values.sort_unstable();
+        let sum: u64 = values.iter().map(|&v| u64::from(v)).sum();
+        let middle = count / 2;
+        let median = if count % 2 == 0 {
+            ((u64::from(values[middle - 1]) + u64::from(values[middle])) / 2) as u32
+        } else {
+            values[middle]
+        };
+        Some(Stats {
+            min: Concentration::from_ppm(values[0]),
+            max: Concentration::from_ppm(values[count - 1]),
+            mean: Concentration::from_ppm(((sum + count as u64 / 2) / count as u64) as u32),
+            median: Concentration::from_ppm(median),
+            count,
+        })
+    }
+}
+
+/// Readings aggregated over one interval of a [`Tier`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bucket {
+    /// Counter value at the start of the interval
+    pub timestamp: u32,
+    pub min: Concentration,
+    pub max: Concentration,
+    /// Mean rounded to the nearest ppm
+    pub mean: Concentration,
+    /// Number of readings in the interval
+    pub count: u16,
+}
+
+/// Last `N` intervals of `period_ms` milliseconds each, aggregated to min/max/mean
+///
+/// Intervals without readings are skipped. Multi-resolution history is kept by recording
+/// every reading into several tiers, e.g. `Tier::<120>::new(60_000)` for the last 2 hours
+/// at 1 minute and `Tier::<48>::new(3_600_000)` for the last 2 days at 1 hour.
+#[derive(Clone, Debug)]
+pub struct Tier<const N: usize> {
+    period_ms: u32,
+    buckets: Ring<Bucket, N>,
+    /// Interval being filled: start, min, max, sum and count
+    current: Option<(u32, Concentration, Concentration, u64, u16)>,
+}
+
+impl<const N: usize> Tier<N> {
+    pub const fn new(period_ms: u32) -> Self {
+        Tier {
+            period_ms,
+            buckets: Ring::new(Bucket {
+                timestamp: 0,
+                min: Concentration::from_ppm(0),
+                max: Concentration::from_ppm(0),
+                mean: Concentration::from_ppm(0),
+                count: 0,
+            }),
+            current: None,
+        }
+    }
+
+    pub fn period_ms(&self) -> u32 {
+        self.period_ms
+    }
+
+    /// Add a reading timestamped with the current counter value
+    pub fn record<C: MonotonicCounter>(&mut self, counter: &C, concentration: Concentration) {
+        self.push(counter.value(), ticks(counter, self.period_ms), concentration);
+    }
+
+    /// Add a reading with an explicit timestamp, `period` is the interval length in ticks
+    pub fn push(&mut self, timestamp: u32, period: u32, concentration: Concentration) {
+        let period = period.max(1);
+        match &mut self.current {
+            Some((start, min, max, sum, count)) if timestamp.wrapping_sub(*start) < period && *count < u16::MAX => {
+                *min = (*min).min(concentration);
+                *max = (*max).max(concentration);
+                *sum += u64::from(concentration.ppm());
+                *count += 1;
+            },
+            current => {
+                // Keep intervals aligned to the first one
+                let start = match current {
+                    Some((start, ..)) => {
+                        let elapsed = timestamp.wrapping_sub(*start);
+                        start.wrapping_add(elapsed - elapsed % period)
+                    },
+                    None => timestamp,
+                };
+                if let Some(bucket) = self.current_bucket() {
+                    self.buckets.push(bucket);
+                }
+                self.current = Some((start, concentration, concentration, u64::from(concentration.ppm()), 1));
+            },
+        }
+    }
+
+    fn current_bucket(&self) -> Option<Bucket> {
+        self.current.map(|(timestamp, min, max, sum, count)| Bucket {
+            timestamp,
+            min,
+            max,
+            mean: Concentration::from_ppm(((sum + u64::from(count) / 2) / u64::from(count)) as u32),
+            count,
+        })
+    }
+
+    /// Number of completed intervals
+    pub fn len(&self) -> usize {
+        self.buckets.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.current = None;
+    }
+
+    /// Returns the interval being filled
+    pub fn current(&self) -> Option<Bucket> {
+        self.current_bucket()
+    }
+
+    /// Iterate over completed intervals from the oldest one to the newest one
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=Bucket> + '_ {
+        self.buckets.iter()
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::StepCounter;
+
+    fn ppm(ppm: u32) -> Concentration {
+        Concentration::from_ppm(ppm)
+    }
+
+    #[test]
+    fn history_drops_the_oldest_readings() {
+        let mut history = History::<3>::new();
+        for i in 0..5 {
+            history.push(i, ppm(400 + i));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.capacity(), 3);
+        assert!(history.iter().map(|e| e.concentration).eq([ppm(402), ppm(403), ppm(404)].iter().copied()));
+        assert_eq!(history.latest(), Some(Entry { timestamp: 4, concentration: ppm(404) }));
+
+        history.clear();
+        assert!(history.is_empty());
+        assert_eq!(history.latest(), None);
+        assert_eq!(history.stats(&StepCounter::default(), Window::All), None);
+    }
+
+    #[test]
+    fn stats_over_all_and_the_last_readings() {
+        let mut history = History::<8>::new();
+        for (i, &value) in [500, 400, 420, 410, 500].iter().enumerate() {
+            history.push(i as u32, ppm(value));
+        }
+        let counter = StepCounter::default();
+        assert_eq!(history.stats(&counter, Window::All), Some(Stats {
+            min: ppm(400),
+            max: ppm(500),
+            mean: ppm(446),
+            median: ppm(420),
+            count: 5,
+        }));
+        // Even count: the median is the mean of 410 and 420, the mean 432.5 rounds up
+        assert_eq!(history.stats(&counter, Window::Last(4)), Some(Stats {
+            min: ppm(400),
+            max: ppm(500),
+            mean: ppm(433),
+            median: ppm(415),
+            count: 4,
+        }));
+        assert_eq!(history.stats(&counter, Window::Last(10)).map(|s| s.count), Some(5));
+        assert_eq!(history.stats(&counter, Window::Last(0)), None);
+    }
+
+    #[test]
+    fn stats_over_recent_readings() {
+        // Every counter read advances the counter by one tick, 2 ticks per millisecond
+        let counter = StepCounter::new(2000);
+        let mut history = History::<8>::new();
+        for i in 0..6 {
+            history.record(&counter, ppm(400 + 10 * i));
+        }
+        // Now is tick 6, 1 ms covers the readings at ticks 4 and 5
+        let stats = history.stats(&counter, Window::Millis(1)).unwrap();
+        assert_eq!((stats.min, stats.max, stats.count), (ppm(440), ppm(450), 2));
+        assert_eq!(history.stats(&counter, Window::Millis(1_000)).map(|s| s.count), Some(6));
+    }
+
+    #[test]
+    fn tier_aligns_intervals_to_the_first_one() {
+        let mut tier = Tier::<4>::new(10);
+        tier.push(3, 10, ppm(400));
+        tier.push(5, 10, ppm(420));
+        tier.push(12, 10, ppm(410));
+        tier.push(13, 10, ppm(500));
+        // Skips the empty intervals starting at 23
+        tier.push(40, 10, ppm(600));
+
+        let mut buckets = tier.iter();
+        assert_eq!(buckets.next(), Some(Bucket { timestamp: 3, min: ppm(400), max: ppm(420), mean: ppm(410), count: 3 }));
+        assert_eq!(buckets.next(), Some(Bucket { timestamp: 13, min: ppm(500), max: ppm(500), mean: ppm(500), count: 1 }));
+        assert_eq!(buckets.next(), None);
+        assert_eq!(tier.current(), Some(Bucket { timestamp: 33, min: ppm(600), max: ppm(600), mean: ppm(600), count: 1 }));
+    }
+
+    #[test]
+    fn tier_keeps_the_last_intervals() {
+        let mut tier = Tier::<2>::new(10);
+        for i in 0..5 {
+            tier.push(10 * i, 10, ppm(400 + i));
+        }
+        assert_eq!(tier.len(), 2);
+        assert!(tier.iter().map(|b| b.timestamp).eq([20, 30].iter().copied()));
+        assert_eq!(tier.current().map(|b| b.timestamp), Some(40));
+
+        tier.clear();
+        assert!(tier.is_empty());
+        assert_eq!(tier.current(), None);
+    }
+
+    #[test]
+    fn tier_splits_full_buckets() {
+        let mut tier = Tier::<2>::new(10);
+        for _ in 0..u16::MAX {
+            tier.push(0, 10, ppm(400));
+        }
+        assert!(tier.is_empty());
+        tier.push(1, 10, ppm(800));
+        assert_eq!(tier.iter().next(), Some(Bucket { timestamp: 0, min: ppm(400), max: ppm(400), mean: ppm(400), count: u16::MAX }));
+        assert_eq!(tier.current(), Some(Bucket { timestamp: 0, min: ppm(800), max: ppm(800), mean: ppm(800), count: 1 }));
+    }
+
+    #[test]
+    fn tier_records_with_the_counter() {
+        // 2 ms intervals at 1 tick per millisecond
+        let counter = StepCounter::default();
+        let mut tier = Tier::<4>::new(2);
+        for i in 0..5 {
+            tier.record(&counter, ppm(400 + i));
+        }
+        assert_eq!(tier.len(), 2);
+        assert_eq!(tier.iter().map(|b| b.count).sum::<u16>(), 4);
+        assert_eq!(tier.current().map(|b| (b.timestamp, b.count)), Some((4, 1)));
+    }
+}
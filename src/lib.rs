@@ -10,7 +10,54 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
-use embedded_hal::serial::{Read, Write};
+pub mod calibration;
+pub mod compensation;
+pub mod concentration;
+pub mod config;
+pub mod correction;
+pub mod history;
+pub mod protocol;
+pub mod quality;
+pub mod serial;
+pub mod warmup;
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub mod asynch;
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub mod tokio_sensor;
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+pub mod logger;
+#[cfg(feature = "embedded-storage")]
+#[cfg_attr(docsrs, doc(cfg(feature = "embedded-storage")))]
+pub mod storage;
+#[cfg(feature = "sqlite")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlite")))]
+pub mod sqlite;
+#[cfg(feature = "sim")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sim")))]
+pub mod sim;
+#[cfg(feature = "embedded-hal-02")]
+#[cfg_attr(docsrs, doc(cfg(feature = "embedded-hal-02")))]
+pub mod fault;
+
+pub use concentration::Concentration;
+pub use config::{Backoff, Config, RetryOn, RetryPolicy};
+use config::DISCARD_LIMIT;
+use protocol::{Co2Concentration, Command, Frame, FrameError, FrameFinder, Request, Response, FRAME_LENGTH};
+pub use protocol::Measurement;
+#[cfg(feature = "experimental")]
+use protocol::{AnalogBounds, DetectionRange, FirmwareVersion};
+#[cfg(feature = "experimental")]
+pub use protocol::UnlimitedMeasurement;
+#[cfg(feature = "async")]
+pub use asynch::AsyncWinsenSensor;
+#[cfg(feature = "tokio")]
+pub use tokio_sensor::TokioWinsenSensor;
+pub use serial::Serial;
+#[cfg(feature = "embedded-io")]
+pub use serial::IoSerial;
 
 pub trait MonotonicCounter {
     /// Returns a measurement of the monotonic counter
@@ -20,6 +67,39 @@ pub trait MonotonicCounter {
     fn frequency(&self) -> u32;
 }
 
+/// Convert milliseconds to ticks of `counter`, `None` if they exceed the counter range
+pub(crate) fn checked_ticks<C: MonotonicCounter>(counter: &C, ms: u32) -> Option<u32> {
+    core::convert::TryFrom::try_from(u64::from(counter.frequency()) * u64::from(ms) / 1000).ok()
+}
+
+/// Convert milliseconds to ticks of `counter`, saturating at the counter range
+pub(crate) fn ticks<C: MonotonicCounter>(counter: &C, ms: u32) -> u32 {
+    checked_ticks(counter, ms).unwrap_or(u32::MAX)
+}
+
+/// Milliseconds elapsed since the value `since` of `counter`
+pub(crate) fn elapsed_ms<C: MonotonicCounter>(counter: &C, since: u32) -> u32 {
+    let ticks = counter.value().wrapping_sub(since);
+    (u64::from(ticks) * 1000 / u64::from(counter.frequency().max(1))) as u32
+}
+
+/// Source of CO2 readings
+///
+/// Implemented by [`WinsenSensor`] and by the wrappers adjusting its readings, so that they
+/// can be stacked, e.g. a [`CorrectedSensor`](correction::CorrectedSensor) of a
+/// [`CompensatedSensor`](compensation::CompensatedSensor).
+pub trait Co2Sensor {
+    type Error;
+
+    /// Read the CO2 gas concentration along with the module temperature and status
+    fn read_measurement(&mut self) -> Result<Measurement, Self::Error>;
+
+    /// Read the CO2 gas concentration
+    fn read_co2_concentration(&mut self) -> Result<Concentration, Self::Error> {
+        self.read_measurement().map(|m| m.co2())
+    }
+}
+
 #[cfg(feature = "std")]
 impl MonotonicCounter for std::time::Instant {
     fn value(&self) -> u32 {
@@ -34,13 +114,19 @@ impl MonotonicCounter for std::time::Instant {
 pub struct WinsenSensor<S, C> {
     serial: S,
     counter: C,
+    config: Config,
 }
 
-impl<E, S: Read<u8, Error=E> + Write<u8, Error=E>, C: MonotonicCounter> WinsenSensor<S, C> {
+impl<S: Serial, C: MonotonicCounter> WinsenSensor<S, C> {
     pub fn new(serial: S, counter: C) -> Self {
+        Self::with_config(serial, counter, Config::default())
+    }
+
+    pub fn with_config(serial: S, counter: C, config: Config) -> Self {
         Self {
             serial,
             counter,
+            config,
         }
     }
 
@@ -48,141 +134,173 @@ impl<E, S: Read<u8, Error=E> + Write<u8, Error=E>, C: MonotonicCounter> WinsenSe
         (self.serial, self.counter)
     }
 
-    pub fn probe(&mut self) -> Result<bool, E> {
-        // Use "Read CO2 concentration" command, ignore the result
+    /// Returns the current configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
-        // MH-Z19B requires two attempts to be made in order to skip the bootloader prompt
-        for _ in 0..2 {
-            match self.simple_command(0x86, &[]) {
-                Ok(_) => return Ok(true),
-                Err(Error::Serial(e)) => return Err(e),
-                // First packets can be corrupted, so ignore protocol errors
-                Err(_) => {},
-            }
-        }
+    /// Replace the configuration
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
 
-        // Final try
-        match self.simple_command(0x86, &[]) {
+    pub fn probe(&mut self) -> Result<bool, S::Error> {
+        // Use "Read CO2 concentration" command, ignore the result
+        match self.transaction_with(Request::ReadCo2Concentration, self.config.retry.for_probe()) {
             Ok(_) => Ok(true),
             Err(Error::Serial(e)) => Err(e),
             Err(_) => Ok(false),
         }
     }
 
-    fn send_packet(&mut self, command: u8, payload: &[u8]) -> Result<(), Error<E>> {
-        assert!(payload.len() <= 5);
-
-        let mut buffer = [0u8; 9];
-        buffer[0] = 0xff;
-        buffer[1] = 0x01;
-        buffer[2] = command;
-        buffer[3..3+payload.len()].copy_from_slice(payload);
-        buffer[8] = checksum(&buffer[..8]);
-
+    /// Busy-wait for `ms` milliseconds
+    fn wait(&self, ms: u32) {
         let t0 = self.counter.value();
-        let dt = self.counter.frequency() / 10; // 100ms in clock ticks
+        let dt = ticks(&self.counter, ms);
+        while self.counter.value().wrapping_sub(t0) < dt {}
+    }
 
-        for b in &buffer {
-            if (self.counter.value().wrapping_sub(t0)) >= dt {
-                return Err(Error::Timeout);
+    fn send_packet(&mut self, frame: &Frame) -> Result<(), Error<S::Error>> {
+        let t0 = self.counter.value();
+        let dt = ticks(&self.counter, self.config.send_timeout_ms);
+
+        for b in frame.as_bytes() {
+            loop {
+                match self.serial.write(*b) {
+                    Ok(()) => break,
+                    Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
+                    Err(nb::Error::WouldBlock) => {},
+                }
+                if (self.counter.value().wrapping_sub(t0)) >= dt {
+                    return Err(Error::Timeout);
+                }
             }
-            match self.serial.write(*b) {
-                Ok(()) => continue,
+        }
+
+        loop {
+            match self.serial.flush() {
+                Ok(()) => return Ok(()),
                 Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
                 Err(nb::Error::WouldBlock) => {},
             }
+            if (self.counter.value().wrapping_sub(t0)) >= dt {
+                return Err(Error::Timeout);
+            }
         }
-        Ok(())
     }
 
-    fn receive_packet(&mut self, command: u8, response: &mut [u8]) -> Result<(), Error<E>> {
-        assert!(response.len() <= 6);
-
-        let mut buffer = [0u8; 9];
-        buffer[0] = 0xff;
-
+    /// Read a single byte, waiting for at most `timeout` clock ticks
+    fn read_byte(&mut self, timeout: u32) -> Result<u8, Error<S::Error>> {
         let t0 = self.counter.value();
-        let dt = self.counter.frequency() / 10; // 100ms in clock ticks
-
-        // Wait for 0xff
         loop {
-            if (self.counter.value().wrapping_sub(t0)) >= dt {
-                return Err(Error::Timeout);
-            }
             match self.serial.read() {
-                Ok(b) => {
-                    if b == 0xff {
-                        break;
-                    }
-                },
+                Ok(b) => return Ok(b),
                 Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
                 Err(nb::Error::WouldBlock) => {},
             }
-        }
-
-        // Read the rest of the response
-        for b in &mut buffer[1..] {
-            if (self.counter.value().wrapping_sub(t0)) >= dt {
+            if (self.counter.value().wrapping_sub(t0)) >= timeout {
                 return Err(Error::Timeout);
             }
+        }
+    }
+
+    /// Discard bytes received before the request is sent
+    fn flush_rx(&mut self) -> Result<(), Error<S::Error>> {
+        for _ in 0..DISCARD_LIMIT {
             match self.serial.read() {
-                Ok(byte) => {
-                    *b = byte;
-                    continue;
-                },
+                Ok(_) => {},
                 Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
-                Err(nb::Error::WouldBlock) => {},
+                Err(nb::Error::WouldBlock) => break,
             }
         }
+        Ok(())
+    }
 
-        // Verify checksum
-        if checksum(&buffer) != 0 {
-            return Err(Error::WrongChecksum);
+    fn receive_packet(&mut self, command: Command) -> Result<Frame, Error<S::Error>> {
+        let mut finder = FrameFinder::new(command);
+        let mut last_error = None;
+
+        // Wait for the first byte of the response, then for each of the following bytes
+        let mut timeout = ticks(&self.counter, self.config.response_timeout_ms);
+        for _ in 0..DISCARD_LIMIT + FRAME_LENGTH {
+            let byte = match self.read_byte(timeout) {
+                Ok(byte) => byte,
+                Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            };
+            timeout = ticks(&self.counter, self.config.inter_byte_timeout_ms);
+
+            match finder.push(byte) {
+                Some(Ok(frame)) => return Ok(frame),
+                // Keep looking for the right frame, but report the error if there is none
+                Some(Err(e)) => last_error = Some(e),
+                None => {},
+            }
         }
 
-        // Check packet type
-        if buffer[1] != command {
-            return Err(Error::WrongPacketType);
+        Err(last_error.map_or(Error::Timeout, Error::from))
+    }
+
+    /// Send the request and receive the response, retrying according to the retry policy
+    fn transaction(&mut self, request: Request) -> Result<Frame, Error<S::Error>> {
+        self.transaction_with(request, self.config.retry)
+    }
+
+    /// Send the request and receive the response, retrying according to `retry`
+    fn transaction_with(&mut self, request: Request, retry: RetryPolicy) -> Result<Frame, Error<S::Error>> {
+        let frame = request.encode();
+
+        let mut attempt = 0;
+        loop {
+            let result = self.exchange(&frame, request.command());
+            match result {
+                Err(ref e) if attempt < retry.retries && retry.is_retryable(e) => {
+                    self.wait(retry.backoff_ms(attempt));
+                    attempt += 1;
+                },
+                result => return result,
+            }
         }
+    }
 
-        response.copy_from_slice(&buffer[2..2+response.len()]);
+    fn exchange(&mut self, frame: &Frame, command: Command) -> Result<Frame, Error<S::Error>> {
+        if self.config.flush_rx {
+            self.flush_rx()?;
+        }
+        self.send_packet(frame)?;
+        self.receive_packet(command)
+    }
 
-        Ok(())
+    fn command<R: Response>(&mut self, request: Request) -> Result<R, Error<S::Error>> {
+        Ok(self.transaction(request)?.decode()?)
     }
 
-    fn command(&mut self, command: u8, payload: &[u8], response: &mut [u8]) -> Result<(), Error<E>> {
-        self.send_packet(command, payload)?;
-        self.receive_packet(command, response)
+    fn simple_command(&mut self, request: Request) -> Result<(), Error<S::Error>> {
+        self.transaction(request).map(|_| ())
     }
 
-    fn simple_command(&mut self, command: u8, payload: &[u8]) -> Result<(), Error<E>> {
-        self.send_packet(command, payload)?;
-        self.receive_packet(command, &mut [])
+    /// Read the CO2 gas concentration
+    pub fn read_co2_concentration(&mut self) -> Result<Concentration, Error<S::Error>> {
+        let response: Co2Concentration = self.command(Request::ReadCo2Concentration)?;
+        Ok(response.ppm.into())
     }
 
-    /// Read the CO2 gas concentration in ppm
-    pub fn read_co2_concentration(&mut self) -> Result<u16, Error<E>> {
-        let mut buf = [0; 2];
-        self.command(0x86, &[], &mut buf)?;
-        Ok(u16::from_be_bytes(buf))
+    /// Read the CO2 gas concentration along with the module temperature and status
+    pub fn read_measurement(&mut self) -> Result<Measurement, Error<S::Error>> {
+        self.command(Request::ReadCo2Concentration)
     }
 
     /// Enable or disable Automatic Baseline Correction (ABC)
-    pub fn set_automatic_baseline_correction(&mut self, enabled: bool) -> Result<(), Error<E>> {
-        let param = if enabled {
-            0xA0
-        } else {
-            0x00
-        };
-        self.simple_command(0x79, &[param])
+    pub fn set_automatic_baseline_correction(&mut self, enabled: bool) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::SetAutomaticBaselineCorrection(enabled))
     }
 
     /// Perform zero point calibration
     ///
     /// For MH-Z19B zero point is 400ppm, please make sure the sensor has
     /// been worked under 400ppm for over 20 minutes
-    pub fn calibrate_zero_point(&mut self) -> Result<(), Error<E>> {
-        self.simple_command(0x87, &[])
+    pub fn calibrate_zero_point(&mut self) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::CalibrateZeroPoint)
     }
 
     /// Perform span point calibration
@@ -191,31 +309,35 @@ impl<E, S: Read<u8, Error=E> + Write<u8, Error=E>, C: MonotonicCounter> WinsenSe
     /// Please make sure the sensor worked under a certain level co2 for over 20 minutes.
     ///
     /// Suggest using 2000ppm as span, at least 1000ppm"
-    pub fn calibrate_span_point(&mut self, span: u16) -> Result<(), Error<E>> {
-        let span = span.to_be_bytes();
-        self.simple_command(0x88, &span)
+    ///
+    /// Spans above 65535 ppm are saturated, they can't be sent to the sensor.
+    pub fn calibrate_span_point(&mut self, span: Concentration) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::CalibrateSpanPoint(span.ppm_u16()))
     }
 
     /// Set the sensor detection range (MH-Z19B only).
     ///
     /// Quoting the datasheet: "Detection range is 2000 or 5000ppm"
-    pub fn set_detection_range(&mut self, range: u32) -> Result<(), Error<E>> {
-        // Note that the actual format differs from what is specified in the datasheet,
-        // at least for MH-Z19B
-        let range = range.to_be_bytes();
-        let mut payload = [0; 5];
-        payload[1..].copy_from_slice(&range);
+    pub fn set_detection_range(&mut self, range: Concentration) -> Result<(), Error<S::Error>> {
+        self.simple_command(Request::SetDetectionRange(range.ppm()))
+    }
 
-        self.simple_command(0x99, &payload)
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    /// Read the CO2 gas concentration without clamping it to the detection range
+    ///
+    /// The response also contains a more precise module temperature and the minimum
+    /// light ADC value, which can be used to detect saturation and aging of the IR source.
+    pub fn read_unlimited_measurement(&mut self) -> Result<UnlimitedMeasurement, Error<S::Error>> {
+        self.command(Request::ReadUnlimitedCo2)
     }
 
     #[cfg(feature = "experimental")]
     #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
     /// Get the sensor detection range
-    pub fn get_detection_range(&mut self) -> Result<u32, Error<E>> {
-        let mut buf = [0; 4];
-        self.command(0x9b, &[], &mut buf)?;
-        Ok(u32::from_be_bytes(buf))
+    pub fn get_detection_range(&mut self) -> Result<Concentration, Error<S::Error>> {
+        let response: DetectionRange = self.command(Request::GetDetectionRange)?;
+        Ok(Concentration::from_ppm(response.range))
     }
 
     #[cfg(feature = "experimental")]
@@ -223,28 +345,36 @@ impl<E, S: Read<u8, Error=E> + Write<u8, Error=E>, C: MonotonicCounter> WinsenSe
     /// Perform sensor reset
     ///
     /// This command resets sensor MCU
-    pub fn reset(&mut self) -> Result<(), Error<E>> {
-        self.send_packet(0x8d, &[])
+    pub fn reset(&mut self) -> Result<(), Error<S::Error>> {
+        self.send_packet(&Request::Reset.encode())
     }
 
     #[cfg(feature = "experimental")]
     #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
     /// Get CO2 concentration bounds used for the analog output
-    pub fn get_analog_bounds(&mut self) -> Result<(u16, u16), Error<E>> {
-        let mut buf = [0; 4];
-        self.command(0xa5, &[], &mut buf)?;
-        let high = u16::from_be_bytes([buf[0], buf[1]]);
-        let low = u16::from_be_bytes([buf[2], buf[3]]);
-        Ok((high, low))
+    pub fn get_analog_bounds(&mut self) -> Result<(Concentration, Concentration), Error<S::Error>> {
+        let response: AnalogBounds = self.command(Request::GetAnalogBounds)?;
+        Ok((response.high.into(), response.low.into()))
     }
 
     #[cfg(feature = "experimental")]
     #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
     /// Get firmware version string
-    pub fn get_firmware_version(&mut self) -> Result<[u8; 4], Error<E>> {
-        let mut buf = [0; 4];
-        self.command(0xa0, &[], &mut buf)?;
-        Ok(buf)
+    pub fn get_firmware_version(&mut self) -> Result<[u8; 4], Error<S::Error>> {
+        let response: FirmwareVersion = self.command(Request::GetFirmwareVersion)?;
+        Ok(response.version)
+    }
+}
+
+impl<S: Serial, C: MonotonicCounter> Co2Sensor for WinsenSensor<S, C> {
+    type Error = Error<S::Error>;
+
+    fn read_measurement(&mut self) -> Result<Measurement, Error<S::Error>> {
+        WinsenSensor::read_measurement(self)
+    }
+
+    fn read_co2_concentration(&mut self) -> Result<Concentration, Error<S::Error>> {
+        WinsenSensor::read_co2_concentration(self)
     }
 }
 
@@ -260,6 +390,157 @@ pub enum Error<E> {
     WrongPacketType,
 }
 
-fn checksum(payload: &[u8]) -> u8 {
-    !payload.iter().fold(0u8, |sum, c| sum.wrapping_add(*c))
+impl<E> Error<E> {
+    /// Short name of the error kind, one of `timeout`, `serial`, `wrong_checksum` and `wrong_packet_type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Timeout => "timeout",
+            Error::Serial(_) => "serial",
+            Error::WrongChecksum => "wrong_checksum",
+            Error::WrongPacketType => "wrong_packet_type",
+        }
+    }
+}
+
+impl<E> From<FrameError> for Error<E> {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::WrongChecksum => Error::WrongChecksum,
+            FrameError::WrongPacketType => Error::WrongPacketType,
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Timeout => f.write_str("request timed out"),
+            Error::Serial(e) => write!(f, "serial port error: {:?}", e),
+            Error::WrongChecksum => f.write_str("wrong checksum"),
+            Error::WrongPacketType => f.write_str("wrong packet type"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: core::fmt::Debug> std::error::Error for Error<E> {}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+
+    fn sensor(curve: Co2Curve) -> WinsenSensor<SimulatedSensor, StepCounter> {
+        WinsenSensor::new(SimulatedSensor::new(curve), StepCounter::default())
+    }
+
+    #[test]
+    fn read_co2_concentration() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn read_measurement() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_temperature(31);
+        sensor.serial.set_status(0x40);
+        let measurement = sensor.read_measurement().unwrap();
+        assert_eq!(measurement.co2_ppm, 800);
+        assert_eq!(measurement.temperature_c, 31);
+        assert_eq!(measurement.status, 0x40);
+    }
+
+    #[test]
+    fn set_automatic_baseline_correction() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.set_automatic_baseline_correction(false).unwrap();
+        assert!(!sensor.serial.automatic_baseline_correction());
+        sensor.set_automatic_baseline_correction(true).unwrap();
+        assert!(sensor.serial.automatic_baseline_correction());
+    }
+
+    #[test]
+    fn calibrate_zero_and_span_point() {
+        let mut sensor = sensor(Co2Curve::Constant(450));
+        sensor.calibrate_zero_point().unwrap();
+        assert_eq!(sensor.serial.zero_calibrations(), 1);
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(400)));
+
+        sensor.serial.set_curve(Co2Curve::Constant(1900));
+        sensor.calibrate_span_point(Concentration::from_ppm(2000)).unwrap();
+        assert_eq!(sensor.serial.span_calibrations(), 1);
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(2000)));
+    }
+
+    #[test]
+    fn set_detection_range() {
+        let mut sensor = sensor(Co2Curve::Constant(3000));
+        sensor.set_detection_range(Concentration::from_ppm(2000)).unwrap();
+        assert_eq!(sensor.serial.detection_range(), 2000);
+        // Readings are clamped to the detection range
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(2000)));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn get_detection_range_and_analog_bounds() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.set_detection_range(Concentration::from_ppm(2000)).unwrap();
+        assert_eq!(sensor.get_detection_range(), Ok(Concentration::from_ppm(2000)));
+        assert_eq!(sensor.get_analog_bounds(), Ok((Concentration::from_ppm(2000), Concentration::from_ppm(0))));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn read_unlimited_measurement() {
+        let mut sensor = sensor(Co2Curve::Constant(3000));
+        sensor.set_detection_range(Concentration::from_ppm(2000)).unwrap();
+        sensor.serial.set_temperature(24);
+        sensor.serial.set_min_light_adc(12345);
+        let measurement = sensor.read_unlimited_measurement().unwrap();
+        assert_eq!(measurement.co2_ppm, 3000);
+        assert_eq!(measurement.temperature_c(), 24.0);
+        assert_eq!(measurement.min_light_adc, 12345);
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn get_firmware_version() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_firmware_version(*b"0430");
+        assert_eq!(sensor.get_firmware_version(), Ok(*b"0430"));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn reset_gets_no_response() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.reset().unwrap();
+        assert_eq!(sensor.serial.resets(), 1);
+        assert_eq!(sensor.serial.read(), Err(nb::Error::WouldBlock));
+        // The next request isn't confused by the reset
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn probe() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        assert_eq!(sensor.probe(), Ok(true));
+    }
+
+    #[test]
+    fn probe_skips_bootloader_prompt() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_bootloader_frames(2);
+        assert_eq!(sensor.probe(), Ok(true));
+        assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let mut sensor = sensor(Co2Curve::Constant(800));
+        sensor.serial.set_bootloader_frames(1);
+        assert_eq!(sensor.read_co2_concentration(), Err(Error::Timeout));
+    }
 }
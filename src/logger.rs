@@ -0,0 +1,602 @@
+//! Data logger writing periodic readings to pluggable sinks.
+//!
+//! [`Logger`] polls a [`WinsenSensor`] and passes every sample to its [`Sink`]s. Failed
+//! requests are recorded too, so gaps in the data are visible. Sinks for rotating CSV files,
+//! JSON Lines and InfluxDB line protocol are provided.
+
+use std::fmt::{self, Debug, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Measurement, MonotonicCounter, Serial, WinsenSensor};
+
+/// Outcome of one sample
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reading {
+    Measurement(Measurement),
+    /// The request failed
+    Error {
+        /// See [`Error::kind`]
+        kind: &'static str,
+        message: String,
+    },
+}
+
+/// Timestamped sample
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub reading: Reading,
+}
+
+impl Record {
+    pub fn new<E: Debug>(timestamp: SystemTime, result: Result<Measurement, Error<E>>) -> Self {
+        let reading = match result {
+            Ok(measurement) => Reading::Measurement(measurement),
+            Err(e) => Reading::Error {
+                kind: e.kind(),
+                message: e.to_string(),
+            },
+        };
+        Record {
+            timestamp,
+            reading,
+        }
+    }
+}
+
+/// Destination for logged records
+pub trait Sink {
+    fn write(&mut self, record: &Record) -> io::Result<()>;
+
+    /// Called after every sample
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Sink + ?Sized> Sink for Box<T> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        (**self).write(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Failure of a sink to write a record
+#[derive(Debug)]
+pub struct SinkError {
+    /// Index of the sink, in the order the sinks were added
+    pub sink: usize,
+    pub error: io::Error,
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sink {} failed: {}", self.sink, self.error)
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Periodic sampler feeding a set of sinks
+pub struct Logger<S, C> {
+    sensor: WinsenSensor<S, C>,
+    sinks: Vec<Box<dyn Sink>>,
+    interval: Duration,
+}
+
+impl<S: Serial, C: MonotonicCounter> Logger<S, C> where S::Error: Debug {
+    pub fn new(sensor: WinsenSensor<S, C>, interval: Duration) -> Self {
+        Logger {
+            sensor,
+            sinks: Vec::new(),
+            interval,
+        }
+    }
+
+    /// Add a sink receiving every record
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut WinsenSensor<S, C> {
+        &mut self.sensor
+    }
+
+    pub fn free(self) -> WinsenSensor<S, C> {
+        self.sensor
+    }
+
+    /// Read the sensor once and write the record to all sinks
+    ///
+    /// A failing sink doesn't prevent writing to the others. Returns the record along
+    /// with the errors of the sinks which failed to write it.
+    pub fn sample(&mut self) -> (Record, Vec<SinkError>) {
+        let result = self.sensor.read_measurement();
+        let record = Record::new(SystemTime::now(), result);
+
+        let mut errors = Vec::new();
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            if let Err(error) = sink.write(&record).and_then(|_| sink.flush()) {
+                errors.push(SinkError {
+                    sink: i,
+                    error,
+                });
+            }
+        }
+        (record, errors)
+    }
+
+    /// Sample the sensor every `interval` until `on_sample` returns [`ControlFlow::Break`]
+    ///
+    /// `on_sample` gets every record and the sink errors of its sample. Failed sinks are
+    /// written to again on the next sample, so e.g. a restarted InfluxDB server is picked up.
+    pub fn run<B>(&mut self, mut on_sample: impl FnMut(&Record, &[SinkError]) -> ControlFlow<B>) -> B {
+        loop {
+            let started = Instant::now();
+            let (record, errors) = self.sample();
+            if let ControlFlow::Break(value) = on_sample(&record, &errors) {
+                return value;
+            }
+            sleep(self.interval.saturating_sub(started.elapsed()));
+        }
+    }
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm, eras are 400 year periods starting on March 1
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn since_epoch(timestamp: SystemTime) -> Duration {
+    timestamp.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Format a timestamp as RFC 3339 in UTC with millisecond precision
+fn format_timestamp(timestamp: SystemTime) -> String {
+    let elapsed = since_epoch(timestamp);
+    let seconds = elapsed.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, time / 3600, time / 60 % 60, time % 60, elapsed.subsec_millis(),
+    )
+}
+
+/// Quote a string for JSON output
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// When [`CsvSink`] starts a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Always append to `<prefix>.csv`
+    Never,
+    /// One `<prefix>-YYYY-MM-DD.csv` file per UTC day
+    Daily,
+    /// Start `<prefix>-YYYY-MM-DDTHH-MM-SS.csv` when the current file reaches the size in bytes
+    Size(u64),
+}
+
+/// CSV files with a `timestamp,co2_ppm,temperature_c,status,error` header
+///
+/// Failed samples have empty measurement columns and the error kind in the last column.
+pub struct CsvSink {
+    directory: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    file: Option<File>,
+    /// Day of the current file for daily rotation
+    day: u64,
+    size: u64,
+}
+
+impl CsvSink {
+    /// Write files named after `prefix` into `directory`, which is created if missing
+    pub fn new(directory: impl Into<PathBuf>, prefix: &str, rotation: Rotation) -> Self {
+        CsvSink {
+            directory: directory.into(),
+            prefix: prefix.into(),
+            rotation,
+            file: None,
+            day: 0,
+            size: 0,
+        }
+    }
+
+    fn open(&mut self, timestamp: SystemTime) -> io::Result<()> {
+        let name = match self.rotation {
+            Rotation::Never => format!("{}.csv", self.prefix),
+            Rotation::Daily => format!("{}-{}.csv", self.prefix, &format_timestamp(timestamp)[..10]),
+            Rotation::Size(_) => format!("{}-{}.csv", self.prefix, format_timestamp(timestamp)[..19].replace(':', "-")),
+        };
+        fs::create_dir_all(&self.directory)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(self.directory.join(name))?;
+        self.size = file.metadata()?.len();
+        if self.size == 0 {
+            let header = b"timestamp,co2_ppm,temperature_c,status,error\n";
+            file.write_all(header)?;
+            self.size = header.len() as u64;
+        }
+        self.file = Some(file);
+        self.day = since_epoch(timestamp).as_secs() / 86400;
+        Ok(())
+    }
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let rotate = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily => since_epoch(record.timestamp).as_secs() / 86400 != self.day,
+            Rotation::Size(limit) => self.size >= limit,
+        };
+        if self.file.is_none() || rotate {
+            self.open(record.timestamp)?;
+        }
+
+        let timestamp = format_timestamp(record.timestamp);
+        let line = match &record.reading {
+            Reading::Measurement(m) => format!("{},{},{},{},\n", timestamp, m.co2_ppm, m.temperature_c, m.status),
+            Reading::Error { kind, .. } => format!("{},,,,{}\n", timestamp, kind),
+        };
+        if let Some(file) = &mut self.file {
+            file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// One JSON object per line
+///
+/// Measurements are written as `{"timestamp":..,"co2_ppm":..,"temperature_c":..,"status":..}`,
+/// failed samples as `{"timestamp":..,"error":..,"message":..}`.
+pub struct JsonLinesSink<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Sink for JsonLinesSink<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let timestamp = json_string(&format_timestamp(record.timestamp));
+        match &record.reading {
+            Reading::Measurement(m) => writeln!(
+                self.writer,
+                "{{\"timestamp\":{},\"co2_ppm\":{},\"temperature_c\":{},\"status\":{}}}",
+                timestamp, m.co2_ppm, m.temperature_c, m.status,
+            ),
+            Reading::Error { kind, message } => writeln!(
+                self.writer,
+                "{{\"timestamp\":{},\"error\":{},\"message\":{}}}",
+                timestamp, json_string(kind), json_string(message),
+            ),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+enum Destination {
+    Writer(Box<dyn Write>),
+    Udp(UdpSocket),
+    Http {
+        address: String,
+        path: String,
+        token: Option<String>,
+    },
+}
+
+/// InfluxDB line protocol
+///
+/// Measurements are written as integer fields `co2_ppm`, `temperature_c` and `status`,
+/// failed samples as a string field `error` holding the error kind. Timestamps have
+/// nanosecond precision.
+pub struct LineProtocolSink {
+    destination: Destination,
+    measurement: String,
+    tags: String,
+}
+
+impl LineProtocolSink {
+    /// Write lines to a file or any other writer
+    pub fn to_writer(writer: impl Write + 'static) -> Self {
+        Self::with_destination(Destination::Writer(Box::new(writer)))
+    }
+
+    /// Send every line as a UDP datagram, e.g. to a Telegraf `socket_listener`
+    pub fn udp(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(address)?;
+        Ok(Self::with_destination(Destination::Udp(socket)))
+    }
+
+    /// POST every line to an HTTP endpoint
+    ///
+    /// `path` includes the query, e.g. `/write?db=co2` for InfluxDB 1.x or
+    /// `/api/v2/write?org=home&bucket=co2&precision=ns` with a token for InfluxDB 2.x.
+    pub fn http(address: &str, path: &str, token: Option<&str>) -> Self {
+        Self::with_destination(Destination::Http {
+            address: address.into(),
+            path: path.into(),
+            token: token.map(Into::into),
+        })
+    }
+
+    fn with_destination(destination: Destination) -> Self {
+        LineProtocolSink {
+            destination,
+            measurement: "co2".into(),
+            tags: String::new(),
+        }
+    }
+
+    /// Set the measurement name [default: co2]
+    pub fn measurement(mut self, name: &str) -> Self {
+        self.measurement = escape(name, &[',', ' ']);
+        self
+    }
+
+    /// Add a tag to every line
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        let _ = write!(self.tags, ",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' ']));
+        self
+    }
+
+    fn line(&self, record: &Record) -> String {
+        let fields = match &record.reading {
+            Reading::Measurement(m) => {
+                format!("co2_ppm={}i,temperature_c={}i,status={}i", m.co2_ppm, m.temperature_c, m.status)
+            },
+            Reading::Error { kind, .. } => format!("error=\"{}\"", escape(kind, &['"', '\\'])),
+        };
+        format!(
+            "{}{} {} {}\n",
+            self.measurement, self.tags, fields, since_epoch(record.timestamp).as_nanos(),
+        )
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn http_post(address: &str, path: &str, token: Option<&str>, body: &str) -> io::Result<()> {
+    let timeout = Duration::from_secs(5);
+    let socket_address = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address not resolved"))?;
+    let stream = TcpStream::connect_timeout(&socket_address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path, address, body.len(),
+    );
+    if let Some(token) = token {
+        let _ = write!(request, "Authorization: Token {}\r\n", token);
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    (&stream).write_all(request.as_bytes())?;
+
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("HTTP request failed: {}", status_line.trim()))),
+    }
+}
+
+impl Sink for LineProtocolSink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let line = self.line(record);
+        match &mut self.destination {
+            Destination::Writer(writer) => writer.write_all(line.as_bytes()),
+            Destination::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Destination::Http { address, path, token } => http_post(address, path, token.as_deref(), &line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.destination {
+            Destination::Writer(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis)
+    }
+
+    fn measurement(timestamp: SystemTime, co2_ppm: u16) -> Record {
+        Record::new::<()>(timestamp, Ok(Measurement {
+            co2_ppm,
+            temperature_c: 24,
+            status: 0,
+            reserved: [0; 2],
+        }))
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(at(1_700_000_000, 123)), "2023-11-14T22:13:20.123Z");
+        // Times before the epoch are clamped
+        assert_eq!(format_timestamp(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("plain µ"), "\"plain µ\"");
+        assert_eq!(json_string("a \"b\" \\ c"), r#""a \"b\" \\ c""#);
+        assert_eq!(json_string("line\n\u{1}"), r#""line\u000a\u0001""#);
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut sink = JsonLinesSink::new(Vec::new());
+        sink.write(&measurement(at(0, 0), 800)).unwrap();
+        sink.write(&Record {
+            timestamp: at(1, 500),
+            reading: Reading::Error {
+                kind: "serial",
+                message: "port \"ttyUSB0\" gone".into(),
+            },
+        }).unwrap();
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(output, concat!(
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"co2_ppm\":800,\"temperature_c\":24,\"status\":0}\n",
+            "{\"timestamp\":\"1970-01-01T00:00:01.500Z\",\"error\":\"serial\",\"message\":\"port \\\"ttyUSB0\\\" gone\"}\n",
+        ));
+    }
+
+    #[test]
+    fn line_protocol_escaping() {
+        let sink = LineProtocolSink::to_writer(io::sink())
+            .measurement("co2 room,1")
+            .tag("room name", "a=b,c");
+        assert_eq!(
+            sink.line(&measurement(at(2, 0), 800)),
+            "co2\\ room\\,1,room\\ name=a\\=b\\,c co2_ppm=800i,temperature_c=24i,status=0i 2000000000\n",
+        );
+        let error = Record::new::<()>(at(3, 0), Err(Error::Timeout));
+        assert_eq!(
+            sink.line(&error),
+            "co2\\ room\\,1,room\\ name=a\\=b\\,c error=\"timeout\" 3000000000\n",
+        );
+    }
+
+    /// Empty directory removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("winsen-logger-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = fs::read_dir(&self.0).unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn csv_without_rotation() {
+        let dir = TempDir::new("never");
+        let mut sink = CsvSink::new(&dir.0, "co2", Rotation::Never);
+        sink.write(&measurement(at(0, 0), 800)).unwrap();
+        sink.write(&Record::new::<()>(at(86_400, 0), Err(Error::WrongChecksum))).unwrap();
+        assert_eq!(dir.files(), ["co2.csv"]);
+        assert_eq!(fs::read_to_string(dir.0.join("co2.csv")).unwrap(), concat!(
+            "timestamp,co2_ppm,temperature_c,status,error\n",
+            "1970-01-01T00:00:00.000Z,800,24,0,\n",
+            "1970-01-02T00:00:00.000Z,,,,wrong_checksum\n",
+        ));
+    }
+
+    #[test]
+    fn csv_daily_rotation() {
+        let dir = TempDir::new("daily");
+        let mut sink = CsvSink::new(&dir.0, "co2", Rotation::Daily);
+        sink.write(&measurement(at(1_700_000_000, 0), 800)).unwrap();
+        sink.write(&measurement(at(1_700_006_399, 0), 810)).unwrap();
+        sink.write(&measurement(at(1_700_006_400, 0), 820)).unwrap();
+        assert_eq!(dir.files(), ["co2-2023-11-14.csv", "co2-2023-11-15.csv"]);
+    }
+
+    #[test]
+    fn csv_size_rotation() {
+        let dir = TempDir::new("size");
+        // The header and one line exceed the limit
+        let mut sink = CsvSink::new(&dir.0, "co2", Rotation::Size(60));
+        sink.write(&measurement(at(1_700_000_000, 0), 800)).unwrap();
+        sink.write(&measurement(at(1_700_000_001, 0), 810)).unwrap();
+        sink.write(&measurement(at(1_700_000_002, 0), 820)).unwrap();
+        assert_eq!(dir.files(), ["co2-2023-11-14T22-13-20.csv", "co2-2023-11-14T22-13-21.csv", "co2-2023-11-14T22-13-22.csv"]);
+    }
+}
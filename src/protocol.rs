@@ -0,0 +1,570 @@
+//! Transport-independent encoding and decoding of sensor packets.
+//!
+//! Every packet exchanged with the sensor is a 9-byte frame starting with `0xFF` and
+//! ending with a checksum. Requests carry a sensor number (always `0x01`), the command
+//! byte and up to 5 bytes of payload. Responses carry the command byte followed by
+//! 6 bytes of data.
+//!
+//! Nothing in this module performs any I/O, so frames can be fed from DMA buffers,
+//! interrupt handlers or test harnesses just as well as from [`WinsenSensor`](crate::WinsenSensor).
+
+use core::convert::TryFrom;
+
+use crate::Concentration;
+
+/// Length of every frame in bytes
+pub const FRAME_LENGTH: usize = 9;
+
+/// First byte of every frame
+pub const START_BYTE: u8 = 0xff;
+
+/// Sensor number used in requests
+const SENSOR_NUMBER: u8 = 0x01;
+
+/// Maximum length of the request payload
+pub const MAX_REQUEST_PAYLOAD: usize = 5;
+
+/// Maximum length of the response data
+pub const MAX_RESPONSE_DATA: usize = 6;
+
+/// Sensor command byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Command {
+    /// Enable or disable Automatic Baseline Correction (ABC)
+    SetAutomaticBaselineCorrection = 0x79,
+    /// Read unclamped CO2 concentration, precise temperature and light ADC value (undocumented)
+    ReadUnlimitedCo2 = 0x85,
+    /// Read CO2 concentration
+    ReadCo2Concentration = 0x86,
+    /// Zero point calibration
+    CalibrateZeroPoint = 0x87,
+    /// Span point calibration
+    CalibrateSpanPoint = 0x88,
+    /// Reset sensor MCU
+    Reset = 0x8d,
+    /// Set detection range
+    SetDetectionRange = 0x99,
+    /// Get detection range
+    GetDetectionRange = 0x9b,
+    /// Get firmware version string
+    GetFirmwareVersion = 0xa0,
+    /// Get CO2 concentration bounds used for the analog output
+    GetAnalogBounds = 0xa5,
+}
+
+impl Command {
+    /// Returns the command byte
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl TryFrom<u8> for Command {
+    /// Unknown command byte
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        match code {
+            0x79 => Ok(Command::SetAutomaticBaselineCorrection),
+            0x85 => Ok(Command::ReadUnlimitedCo2),
+            0x86 => Ok(Command::ReadCo2Concentration),
+            0x87 => Ok(Command::CalibrateZeroPoint),
+            0x88 => Ok(Command::CalibrateSpanPoint),
+            0x8d => Ok(Command::Reset),
+            0x99 => Ok(Command::SetDetectionRange),
+            0x9b => Ok(Command::GetDetectionRange),
+            0xa0 => Ok(Command::GetFirmwareVersion),
+            0xa5 => Ok(Command::GetAnalogBounds),
+            _ => Err(code),
+        }
+    }
+}
+
+/// Frame decoding error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// Frame doesn't start with `0xFF` or has the wrong checksum
+    WrongChecksum,
+    /// The frame is a response to a different command
+    WrongPacketType,
+}
+
+/// A single 9-byte packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame([u8; FRAME_LENGTH]);
+
+impl Frame {
+    /// Build a request frame, as sent to the sensor
+    ///
+    /// Panics if `payload` is longer than [`MAX_REQUEST_PAYLOAD`] bytes.
+    pub fn request(command: Command, payload: &[u8]) -> Self {
+        assert!(payload.len() <= MAX_REQUEST_PAYLOAD);
+
+        let mut buffer = [0u8; FRAME_LENGTH];
+        buffer[0] = START_BYTE;
+        buffer[1] = SENSOR_NUMBER;
+        buffer[2] = command.code();
+        buffer[3..3+payload.len()].copy_from_slice(payload);
+        buffer[8] = checksum(&buffer[..8]);
+        Frame(buffer)
+    }
+
+    /// Build a response frame, as sent by the sensor
+    ///
+    /// Panics if `data` is longer than [`MAX_RESPONSE_DATA`] bytes.
+    pub fn response(command: Command, data: &[u8]) -> Self {
+        assert!(data.len() <= MAX_RESPONSE_DATA);
+
+        let mut buffer = [0u8; FRAME_LENGTH];
+        buffer[0] = START_BYTE;
+        buffer[1] = command.code();
+        buffer[2..2+data.len()].copy_from_slice(data);
+        buffer[8] = checksum(&buffer[..8]);
+        Frame(buffer)
+    }
+
+    /// Validate the start byte and the checksum of a received frame
+    pub fn from_bytes(bytes: [u8; FRAME_LENGTH]) -> Result<Self, FrameError> {
+        if bytes[0] != START_BYTE || checksum(&bytes) != 0 {
+            return Err(FrameError::WrongChecksum);
+        }
+        Ok(Frame(bytes))
+    }
+
+    /// Returns raw frame bytes
+    pub fn as_bytes(&self) -> &[u8; FRAME_LENGTH] {
+        &self.0
+    }
+
+    /// Command byte of a request frame
+    pub fn request_command(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// Payload of a request frame
+    pub fn request_payload(&self) -> &[u8] {
+        &self.0[3..8]
+    }
+
+    /// Command byte of a response frame
+    pub fn response_command(&self) -> u8 {
+        self.0[1]
+    }
+
+    /// Data of a response frame
+    pub fn response_data(&self) -> &[u8] {
+        &self.0[2..8]
+    }
+
+    /// Check that this frame is a response to `command` and return its data
+    pub fn expect_response(&self, command: Command) -> Result<&[u8], FrameError> {
+        if self.response_command() != command.code() {
+            return Err(FrameError::WrongPacketType);
+        }
+        Ok(self.response_data())
+    }
+
+    /// Decode a typed response
+    pub fn decode<R: Response>(&self) -> Result<R, FrameError> {
+        self.expect_response(R::COMMAND).map(R::from_data)
+    }
+}
+
+/// Sliding-window response frame finder
+///
+/// Bytes received from the sensor are pushed one by one. Candidate frames start at
+/// `0xFF` bytes and are validated by checksum and command byte, so `0xFF` bytes inside
+/// payloads and leftovers of previous transactions don't leave the stream misaligned.
+#[derive(Clone, Debug)]
+pub struct FrameFinder {
+    command: Command,
+    buffer: [u8; FRAME_LENGTH],
+    len: usize,
+}
+
+impl FrameFinder {
+    /// Create a finder for responses to `command`
+    pub fn new(command: Command) -> Self {
+        FrameFinder {
+            command,
+            buffer: [0; FRAME_LENGTH],
+            len: 0,
+        }
+    }
+
+    /// Returns `true` if no candidate frame is being collected
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drop the candidate frame being collected
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Push a received byte
+    ///
+    /// Returns `Some(Ok(frame))` when a valid response is found and `Some(Err(e))` when
+    /// a candidate frame is rejected. Bytes outside of candidate frames are silently discarded.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, FrameError>> {
+        if self.len == 0 && byte != START_BYTE {
+            return None;
+        }
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        if self.len < FRAME_LENGTH {
+            return None;
+        }
+
+        match Frame::from_bytes(self.buffer) {
+            Ok(frame) => {
+                // A complete frame, either the one we are looking for or a stale one
+                self.len = 0;
+                if frame.response_command() == self.command.code() {
+                    Some(Ok(frame))
+                } else {
+                    Some(Err(FrameError::WrongPacketType))
+                }
+            },
+            Err(e) => {
+                // Resynchronize on the next 0xff inside the window
+                match self.buffer[1..].iter().position(|b| *b == START_BYTE) {
+                    Some(i) => {
+                        self.buffer.copy_within(i + 1.., 0);
+                        self.len = FRAME_LENGTH - (i + 1);
+                    },
+                    None => self.len = 0,
+                }
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Request to the sensor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Read CO2 concentration
+    ReadCo2Concentration,
+    /// Read unclamped CO2 concentration, precise temperature and light ADC value
+    ReadUnlimitedCo2,
+    /// Enable or disable Automatic Baseline Correction (ABC)
+    SetAutomaticBaselineCorrection(bool),
+    /// Zero point calibration
+    CalibrateZeroPoint,
+    /// Span point calibration with the given span in ppm
+    CalibrateSpanPoint(u16),
+    /// Set detection range in ppm
+    SetDetectionRange(u32),
+    /// Get detection range
+    GetDetectionRange,
+    /// Reset sensor MCU
+    Reset,
+    /// Get CO2 concentration bounds used for the analog output
+    GetAnalogBounds,
+    /// Get firmware version string
+    GetFirmwareVersion,
+}
+
+impl Request {
+    /// Returns the command byte of this request
+    pub fn command(&self) -> Command {
+        match self {
+            Request::ReadCo2Concentration => Command::ReadCo2Concentration,
+            Request::ReadUnlimitedCo2 => Command::ReadUnlimitedCo2,
+            Request::SetAutomaticBaselineCorrection(_) => Command::SetAutomaticBaselineCorrection,
+            Request::CalibrateZeroPoint => Command::CalibrateZeroPoint,
+            Request::CalibrateSpanPoint(_) => Command::CalibrateSpanPoint,
+            Request::SetDetectionRange(_) => Command::SetDetectionRange,
+            Request::GetDetectionRange => Command::GetDetectionRange,
+            Request::Reset => Command::Reset,
+            Request::GetAnalogBounds => Command::GetAnalogBounds,
+            Request::GetFirmwareVersion => Command::GetFirmwareVersion,
+        }
+    }
+
+    /// Encode the request into a frame
+    pub fn encode(&self) -> Frame {
+        let command = self.command();
+        match *self {
+            Request::SetAutomaticBaselineCorrection(enabled) => {
+                let param = if enabled {
+                    0xA0
+                } else {
+                    0x00
+                };
+                Frame::request(command, &[param])
+            },
+            Request::CalibrateSpanPoint(span) => {
+                Frame::request(command, &span.to_be_bytes())
+            },
+            Request::SetDetectionRange(range) => {
+                // Note that the actual format differs from what is specified in the datasheet,
+                // at least for MH-Z19B
+                let range = range.to_be_bytes();
+                let mut payload = [0; 5];
+                payload[1..].copy_from_slice(&range);
+                Frame::request(command, &payload)
+            },
+            _ => Frame::request(command, &[]),
+        }
+    }
+
+    /// Decode a request frame, as received by the sensor
+    ///
+    /// Returns `None` for unknown commands.
+    pub fn decode(frame: &Frame) -> Option<Self> {
+        let payload = frame.request_payload();
+        let request = match Command::try_from(frame.request_command()).ok()? {
+            Command::ReadCo2Concentration => Request::ReadCo2Concentration,
+            Command::ReadUnlimitedCo2 => Request::ReadUnlimitedCo2,
+            Command::SetAutomaticBaselineCorrection => Request::SetAutomaticBaselineCorrection(payload[0] == 0xA0),
+            Command::CalibrateZeroPoint => Request::CalibrateZeroPoint,
+            Command::CalibrateSpanPoint => Request::CalibrateSpanPoint(u16::from_be_bytes([payload[0], payload[1]])),
+            Command::SetDetectionRange => {
+                Request::SetDetectionRange(u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]))
+            },
+            Command::GetDetectionRange => Request::GetDetectionRange,
+            Command::Reset => Request::Reset,
+            Command::GetAnalogBounds => Request::GetAnalogBounds,
+            Command::GetFirmwareVersion => Request::GetFirmwareVersion,
+        };
+        Some(request)
+    }
+}
+
+/// Typed response data
+pub trait Response: Sized {
+    /// Command this type is a response to
+    const COMMAND: Command;
+
+    /// Decode response data, `data` is always [`MAX_RESPONSE_DATA`] bytes long
+    fn from_data(data: &[u8]) -> Self;
+}
+
+/// Response to [`Command::ReadCo2Concentration`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Co2Concentration {
+    /// CO2 concentration in ppm
+    pub ppm: u16,
+}
+
+impl Response for Co2Concentration {
+    const COMMAND: Command = Command::ReadCo2Concentration;
+
+    fn from_data(data: &[u8]) -> Self {
+        Co2Concentration {
+            ppm: u16::from_be_bytes([data[0], data[1]]),
+        }
+    }
+}
+
+/// Full response to [`Command::ReadCo2Concentration`]
+///
+/// Besides the CO2 concentration, MH-Z19 and MH-Z19B report the module temperature
+/// and a status byte in the same response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Measurement {
+    /// CO2 concentration in ppm
+    pub co2_ppm: u16,
+    /// Module temperature in degrees Celsius
+    ///
+    /// This is the temperature of the sensor itself, which is usually a few degrees
+    /// above the ambient temperature.
+    pub temperature_c: i16,
+    /// Status byte, its meaning is not documented by the manufacturer
+    ///
+    /// The value differs while the sensor is preheating.
+    pub status: u8,
+    /// Remaining undocumented bytes of the response
+    pub reserved: [u8; 2],
+}
+
+impl Measurement {
+    /// Returns the CO2 concentration
+    pub fn co2(&self) -> Concentration {
+        self.co2_ppm.into()
+    }
+}
+
+impl Response for Measurement {
+    const COMMAND: Command = Command::ReadCo2Concentration;
+
+    fn from_data(data: &[u8]) -> Self {
+        Measurement {
+            co2_ppm: u16::from_be_bytes([data[0], data[1]]),
+            // Temperature is transmitted with an offset of 40
+            temperature_c: i16::from(data[2]) - 40,
+            status: data[3],
+            reserved: [data[4], data[5]],
+        }
+    }
+}
+
+/// Response to [`Command::ReadUnlimitedCo2`]
+///
+/// This command is not documented by the manufacturer, the layout was reverse-engineered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnlimitedMeasurement {
+    /// Raw temperature value, see [`UnlimitedMeasurement::temperature_c`]
+    pub temperature_raw: [u8; 2],
+    /// CO2 concentration in ppm, not clamped to the detection range
+    pub co2_ppm: u16,
+    /// Minimum light ADC value
+    ///
+    /// This value decreases as the IR source ages.
+    pub min_light_adc: u16,
+}
+
+impl UnlimitedMeasurement {
+    /// Module temperature in degrees Celsius with a fractional part
+    pub fn temperature_c(&self) -> f32 {
+        let [high, low] = self.temperature_raw;
+        (f32::from(high) - 8.0) * 15.0 + f32::from(low) / 17.0
+    }
+}
+
+impl Response for UnlimitedMeasurement {
+    const COMMAND: Command = Command::ReadUnlimitedCo2;
+
+    fn from_data(data: &[u8]) -> Self {
+        UnlimitedMeasurement {
+            temperature_raw: [data[0], data[1]],
+            co2_ppm: u16::from_be_bytes([data[2], data[3]]),
+            min_light_adc: u16::from_be_bytes([data[4], data[5]]),
+        }
+    }
+}
+
+/// Response to [`Command::GetDetectionRange`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DetectionRange {
+    /// Detection range in ppm
+    pub range: u32,
+}
+
+impl Response for DetectionRange {
+    const COMMAND: Command = Command::GetDetectionRange;
+
+    fn from_data(data: &[u8]) -> Self {
+        DetectionRange {
+            range: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        }
+    }
+}
+
+/// Response to [`Command::GetAnalogBounds`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnalogBounds {
+    /// CO2 concentration in ppm corresponding to the highest output level
+    pub high: u16,
+    /// CO2 concentration in ppm corresponding to the lowest output level
+    pub low: u16,
+}
+
+impl Response for AnalogBounds {
+    const COMMAND: Command = Command::GetAnalogBounds;
+
+    fn from_data(data: &[u8]) -> Self {
+        AnalogBounds {
+            high: u16::from_be_bytes([data[0], data[1]]),
+            low: u16::from_be_bytes([data[2], data[3]]),
+        }
+    }
+}
+
+/// Response to [`Command::GetFirmwareVersion`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    /// Firmware version string, e.g. `b"0430"`
+    pub version: [u8; 4],
+}
+
+impl Response for FirmwareVersion {
+    const COMMAND: Command = Command::GetFirmwareVersion;
+
+    fn from_data(data: &[u8]) -> Self {
+        FirmwareVersion {
+            version: [data[0], data[1], data[2], data[3]],
+        }
+    }
+}
+
+fn checksum(payload: &[u8]) -> u8 {
+    !payload.iter().fold(0u8, |sum, c| sum.wrapping_add(*c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Push `bytes`, returning the first valid frame and the number of rejected candidates before it
+    fn find(finder: &mut FrameFinder, bytes: &[u8]) -> (Option<Frame>, usize) {
+        let mut rejected = 0;
+        for (i, b) in bytes.iter().enumerate() {
+            match finder.push(*b) {
+                Some(Ok(frame)) => {
+                    assert_eq!(i, bytes.len() - 1, "frame found before the last byte");
+                    return (Some(frame), rejected);
+                },
+                Some(Err(_)) => rejected += 1,
+                None => {},
+            }
+        }
+        (None, rejected)
+    }
+
+    fn concat(a: &[u8], b: &[u8]) -> ([u8; 32], usize) {
+        let mut buffer = [0; 32];
+        buffer[..a.len()].copy_from_slice(a);
+        buffer[a.len()..a.len() + b.len()].copy_from_slice(b);
+        (buffer, a.len() + b.len())
+    }
+
+    #[test]
+    fn garbage_before_frame() {
+        let frame = Frame::response(Command::ReadCo2Concentration, &[0x02, 0x58, 0x41, 0, 0, 0]);
+        let (bytes, len) = concat(&[0x00, 0x86, 0x12, 0xfe], frame.as_bytes());
+
+        let mut finder = FrameFinder::new(Command::ReadCo2Concentration);
+        assert_eq!(find(&mut finder, &bytes[..len]), (Some(frame), 0));
+        assert!(finder.is_empty());
+    }
+
+    #[test]
+    fn stale_frame_before_frame() {
+        let stale = Frame::response(Command::GetDetectionRange, &[0, 0, 0x13, 0x88, 0, 0]);
+        let frame = Frame::response(Command::ReadCo2Concentration, &[0x02, 0x58, 0x41, 0, 0, 0]);
+        let (bytes, len) = concat(stale.as_bytes(), frame.as_bytes());
+
+        let mut finder = FrameFinder::new(Command::ReadCo2Concentration);
+        let mut results = bytes[..FRAME_LENGTH].iter().filter_map(|b| finder.push(*b));
+        assert_eq!(results.next(), Some(Err(FrameError::WrongPacketType)));
+        assert_eq!(results.next(), None);
+        assert_eq!(find(&mut finder, &bytes[FRAME_LENGTH..len]), (Some(frame), 0));
+    }
+
+    #[test]
+    fn truncated_frame_before_frame_with_start_byte_in_data() {
+        let truncated = Frame::response(Command::ReadCo2Concentration, &[0x03, 0x20, 0x41, 0, 0, 0]);
+        let frame = Frame::response(Command::ReadCo2Concentration, &[0x01, 0xff, 0x41, 0, 0, 0]);
+        let (bytes, len) = concat(&truncated.as_bytes()[..4], frame.as_bytes());
+
+        let mut finder = FrameFinder::new(Command::ReadCo2Concentration);
+        let (found, rejected) = find(&mut finder, &bytes[..len]);
+        assert_eq!(found, Some(frame));
+        assert!(rejected > 0);
+    }
+
+    #[test]
+    fn stray_start_byte_before_frame() {
+        let frame = Frame::response(Command::ReadCo2Concentration, &[0x02, 0x58, 0x41, 0, 0, 0]);
+        let (bytes, len) = concat(&[START_BYTE], frame.as_bytes());
+
+        let mut finder = FrameFinder::new(Command::ReadCo2Concentration);
+        let (found, rejected) = find(&mut finder, &bytes[..len]);
+        assert_eq!(found, Some(frame));
+        assert_eq!(rejected, 1);
+    }
+}
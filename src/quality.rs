@@ -0,0 +1,293 @@
+//! Reading validity classification.
+//!
+//! The sensor reports clamped values at the detection range ceiling, may freeze at one value
+//! and occasionally returns spikes. [`QualityChecker`] flags such readings, [`QualitySensor`]
+//! attaches the flag to every reading of a [`WinsenSensor`].
+
+use crate::{Concentration, Error, Measurement, MonotonicCounter, Serial, WinsenSensor};
+
+/// Reading quality flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    Ok,
+    /// The reading is at the detection range ceiling, the real value may be higher
+    Saturated,
+    /// The reading hasn't changed for [`QualityConfig::stuck_samples`] samples
+    Stuck,
+    /// The reading is below the outdoor floor or changed faster than physically possible
+    Implausible,
+}
+
+/// Classification thresholds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QualityConfig {
+    /// Detection range of the sensor
+    pub detection_range: Concentration,
+    /// Lowest plausible concentration, outdoor air is above ~400 ppm
+    pub floor: Concentration,
+    /// Number of identical consecutive readings after which the output is considered stuck, 0 disables the check
+    pub stuck_samples: u32,
+    /// Highest plausible rate of change in ppm per second
+    pub max_rate_ppm_per_s: u32,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            detection_range: Concentration::from_ppm(5000),
+            floor: Concentration::from_ppm(350),
+            stuck_samples: 60,
+            max_rate_ppm_per_s: 100,
+        }
+    }
+}
+
+impl QualityConfig {
+    /// Set the detection range
+    pub fn detection_range(mut self, range: Concentration) -> Self {
+        self.detection_range = range;
+        self
+    }
+
+    /// Set the lowest plausible concentration
+    pub fn floor(mut self, floor: Concentration) -> Self {
+        self.floor = floor;
+        self
+    }
+
+    /// Set the number of identical readings after which the output is considered stuck
+    pub fn stuck_samples(mut self, samples: u32) -> Self {
+        self.stuck_samples = samples;
+        self
+    }
+
+    /// Set the highest plausible rate of change
+    pub fn max_rate_ppm_per_s(mut self, rate: u32) -> Self {
+        self.max_rate_ppm_per_s = rate;
+        self
+    }
+}
+
+/// Reading with its quality flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checked<T> {
+    pub value: T,
+    pub quality: Quality,
+}
+
+/// Stateful reading classifier
+#[derive(Clone, Debug)]
+pub struct QualityChecker {
+    config: QualityConfig,
+    /// Last reading that passed the plausibility checks and its counter value
+    last_plausible: Option<(Concentration, u32)>,
+    /// Last reading and the number of times it was repeated in a row
+    last: Option<(Concentration, u32)>,
+}
+
+impl QualityChecker {
+    pub fn new(config: QualityConfig) -> Self {
+        QualityChecker {
+            config,
+            last_plausible: None,
+            last: None,
+        }
+    }
+
+    pub fn config(&self) -> &QualityConfig {
+        &self.config
+    }
+
+    /// Change the thresholds, e.g. after changing the detection range
+    pub fn set_config(&mut self, config: QualityConfig) {
+        self.config = config;
+    }
+
+    /// Forget previous readings
+    pub fn reset(&mut self) {
+        self.last_plausible = None;
+        self.last = None;
+    }
+
+    /// Classify a reading taken now
+    ///
+    /// Saturation takes precedence over implausibility, which takes precedence over stuck output.
+    pub fn check<C: MonotonicCounter>(&mut self, counter: &C, concentration: Concentration) -> Quality {
+        let now = counter.value();
+
+        let repeated = match self.last {
+            Some((value, count)) if value == concentration => count.saturating_add(1),
+            _ => 1,
+        };
+        self.last = Some((concentration, repeated));
+
+        if concentration >= self.config.detection_range {
+            return Quality::Saturated;
+        }
+        if concentration < self.config.floor {
+            return Quality::Implausible;
+        }
+        if let Some((value, timestamp)) = self.last_plausible {
+            let elapsed = now.wrapping_sub(timestamp);
+            let change = u64::from(concentration.ppm().abs_diff(value.ppm()));
+            // change / (elapsed / frequency) > max_rate, without division
+            let max_change = u64::from(self.config.max_rate_ppm_per_s) * u64::from(elapsed);
+            if elapsed > 0 && change * u64::from(counter.frequency()) > max_change {
+                return Quality::Implausible;
+            }
+        }
+        self.last_plausible = Some((concentration, now));
+
+        if self.config.stuck_samples > 0 && repeated >= self.config.stuck_samples {
+            Quality::Stuck
+        } else {
+            Quality::Ok
+        }
+    }
+}
+
+/// [`WinsenSensor`] wrapper attaching a quality flag to every reading
+pub struct QualitySensor<S, C> {
+    sensor: WinsenSensor<S, C>,
+    checker: QualityChecker,
+}
+
+impl<S: Serial, C: MonotonicCounter> QualitySensor<S, C> {
+    pub fn new(sensor: WinsenSensor<S, C>, config: QualityConfig) -> Self {
+        QualitySensor {
+            sensor,
+            checker: QualityChecker::new(config),
+        }
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut WinsenSensor<S, C> {
+        &mut self.sensor
+    }
+
+    pub fn checker_mut(&mut self) -> &mut QualityChecker {
+        &mut self.checker
+    }
+
+    pub fn free(self) -> WinsenSensor<S, C> {
+        self.sensor
+    }
+
+    /// Read the CO2 concentration and classify it
+    pub fn read_co2_concentration(&mut self) -> Result<Checked<Concentration>, Error<S::Error>> {
+        let concentration = self.sensor.read_co2_concentration()?;
+        let quality = self.checker.check(&self.sensor.counter, concentration);
+        Ok(Checked {
+            value: concentration,
+            quality,
+        })
+    }
+
+    /// Read a measurement and classify its CO2 concentration
+    pub fn read_measurement(&mut self) -> Result<Checked<Measurement>, Error<S::Error>> {
+        let measurement = self.sensor.read_measurement()?;
+        let quality = self.checker.check(&self.sensor.counter, measurement.co2());
+        Ok(Checked {
+            value: measurement,
+            quality,
+        })
+    }
+
+    /// Update the detection range used for saturation checks from the sensor
+    #[cfg(feature = "experimental")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental")))]
+    pub fn update_detection_range(&mut self) -> Result<Concentration, Error<S::Error>> {
+        let range = self.sensor.get_detection_range()?;
+        let config = self.checker.config().detection_range(range);
+        self.checker.set_config(config);
+        Ok(range)
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+
+    fn ppm(ppm: u32) -> Concentration {
+        Concentration::from_ppm(ppm)
+    }
+
+    /// Checks the readings one counter tick apart, one tick per second
+    fn check_all(config: QualityConfig, readings: &[u32]) -> Quality {
+        let counter = StepCounter::new(1);
+        let mut checker = QualityChecker::new(config);
+        let mut quality = Quality::Ok;
+        for &reading in readings {
+            quality = checker.check(&counter, ppm(reading));
+        }
+        quality
+    }
+
+    #[test]
+    fn saturated_at_the_detection_range() {
+        let config = QualityConfig::default().detection_range(ppm(2000));
+        assert_eq!(check_all(config, &[2000]), Quality::Saturated);
+        assert_eq!(check_all(config, &[1999]), Quality::Ok);
+    }
+
+    #[test]
+    fn implausible_below_the_floor() {
+        assert_eq!(check_all(QualityConfig::default(), &[349]), Quality::Implausible);
+        assert_eq!(check_all(QualityConfig::default(), &[350]), Quality::Ok);
+        assert_eq!(check_all(QualityConfig::default().floor(ppm(0)), &[10]), Quality::Ok);
+    }
+
+    #[test]
+    fn implausible_rate_of_change() {
+        let counter = StepCounter::new(1);
+        let mut checker = QualityChecker::new(QualityConfig::default().max_rate_ppm_per_s(100));
+        assert_eq!(checker.check(&counter, ppm(800)), Quality::Ok);
+        assert_eq!(checker.check(&counter, ppm(900)), Quality::Ok);
+        assert_eq!(checker.check(&counter, ppm(1001)), Quality::Implausible);
+        assert_eq!(checker.check(&counter, ppm(650)), Quality::Implausible);
+        // The rate is measured from the last plausible reading, 3 seconds ago
+        assert_eq!(checker.check(&counter, ppm(1200)), Quality::Ok);
+
+        checker.reset();
+        assert_eq!(checker.check(&counter, ppm(4000)), Quality::Ok);
+    }
+
+    #[test]
+    fn rate_check_at_a_fast_counter() {
+        // 1 µs between readings allows no change at all, without overflowing at 1 MHz
+        let counter = StepCounter::new(1_000_000);
+        let mut checker = QualityChecker::new(QualityConfig::default().max_rate_ppm_per_s(u32::MAX));
+        assert_eq!(checker.check(&counter, ppm(400)), Quality::Ok);
+        assert_eq!(checker.check(&counter, ppm(4000)), Quality::Ok);
+        let mut checker = QualityChecker::new(QualityConfig::default().max_rate_ppm_per_s(100));
+        assert_eq!(checker.check(&counter, ppm(400)), Quality::Ok);
+        assert_eq!(checker.check(&counter, ppm(401)), Quality::Implausible);
+    }
+
+    #[test]
+    fn stuck_output() {
+        let config = QualityConfig::default().stuck_samples(3);
+        assert_eq!(check_all(config, &[800, 800]), Quality::Ok);
+        assert_eq!(check_all(config, &[800, 800, 800]), Quality::Stuck);
+        assert_eq!(check_all(config, &[800, 800, 800, 801]), Quality::Ok);
+        assert_eq!(check_all(config.stuck_samples(0), &[800; 100]), Quality::Ok);
+    }
+
+    #[test]
+    fn precedence() {
+        let config = QualityConfig::default().detection_range(ppm(2000)).stuck_samples(2);
+        // Saturation over implausible rate and stuck output
+        assert_eq!(check_all(config, &[800, 2000]), Quality::Saturated);
+        assert_eq!(check_all(config, &[2000, 2000, 2000]), Quality::Saturated);
+        // Implausibility over stuck output
+        assert_eq!(check_all(config, &[300, 300, 300]), Quality::Implausible);
+    }
+
+    #[test]
+    fn quality_sensor() {
+        let sensor = WinsenSensor::new(SimulatedSensor::new(Co2Curve::Constant(800)), StepCounter::default());
+        let mut sensor = QualitySensor::new(sensor, QualityConfig::default().stuck_samples(2));
+        assert_eq!(sensor.read_co2_concentration(), Ok(Checked { value: ppm(800), quality: Quality::Ok }));
+        assert_eq!(sensor.read_measurement().map(|m| m.quality), Ok(Quality::Stuck));
+    }
+}
@@ -0,0 +1,88 @@
+//! Serial port abstraction used by [`WinsenSensor`](crate::WinsenSensor).
+//!
+//! Any `embedded-hal` 0.2 serial port implements [`Serial`] directly, ports
+//! implementing `embedded-io` traits have to be wrapped into [`IoSerial`].
+
+/// Non-blocking byte-oriented serial port
+pub trait Serial {
+    /// Serial port error
+    type Error;
+
+    /// Read a single byte
+    fn read(&mut self) -> nb::Result<u8, Self::Error>;
+
+    /// Write a single byte
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error>;
+
+    /// Ensure that all written bytes are transmitted
+    fn flush(&mut self) -> nb::Result<(), Self::Error>;
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<E, S: embedded_hal::serial::Read<u8, Error=E> + embedded_hal::serial::Write<u8, Error=E>> Serial for S {
+    type Error = E;
+
+    fn read(&mut self) -> nb::Result<u8, E> {
+        embedded_hal::serial::Read::read(self)
+    }
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), E> {
+        embedded_hal::serial::Write::write(self, byte)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), E> {
+        embedded_hal::serial::Write::flush(self)
+    }
+}
+
+/// Adapter for serial ports implementing `embedded-io` traits
+///
+/// `ReadReady` and `WriteReady` are used to avoid blocking, so that
+/// [`WinsenSensor`](crate::WinsenSensor) timeouts keep working.
+#[cfg(feature = "embedded-io")]
+#[cfg_attr(docsrs, doc(cfg(feature = "embedded-io")))]
+pub struct IoSerial<T>(T);
+
+#[cfg(feature = "embedded-io")]
+impl<T> IoSerial<T> {
+    pub fn new(inner: T) -> Self {
+        IoSerial(inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> Serial for IoSerial<T>
+where
+    T: embedded_io::Read + embedded_io::ReadReady + embedded_io::Write + embedded_io::WriteReady,
+{
+    type Error = T::Error;
+
+    fn read(&mut self) -> nb::Result<u8, T::Error> {
+        if !self.0.read_ready()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut byte = [0u8; 1];
+        match self.0.read(&mut byte)? {
+            1 => Ok(byte[0]),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), T::Error> {
+        if !self.0.write_ready()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        match self.0.write(&[byte])? {
+            1 => Ok(()),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), T::Error> {
+        Ok(self.0.flush()?)
+    }
+}
@@ -0,0 +1,463 @@
+//! Emulated MH-Z19B for testing code built on [`WinsenSensor`](crate::WinsenSensor) without hardware.
+//!
+//! [`SimulatedSensor`] implements `embedded-hal` serial traits and answers every request
+//! the driver sends, [`StepCounter`] provides deterministic time for the driver timeouts.
+//! With the `async` feature the sensor also implements `embedded-io-async` traits, and
+//! [`NoDelay`] drives [`AsyncWinsenSensor`](crate::AsyncWinsenSensor) timeouts.
+
+use core::cell::Cell;
+use core::convert::Infallible;
+
+use embedded_hal::serial::{Read, Write};
+
+use crate::MonotonicCounter;
+use crate::protocol::{Command, Frame, Request, FRAME_LENGTH, START_BYTE};
+
+/// Capacity of the transmit queue in bytes
+const TX_CAPACITY: usize = 4 * FRAME_LENGTH;
+
+/// Zero point concentration in ppm
+const ZERO_POINT: f32 = 400.0;
+
+/// Text sent instead of the first responses after power-on
+const BOOTLOADER_PROMPT: &[u8] = b"\r\nBOOT\r\n";
+
+/// CO2 concentration produced by the emulated sensor
+#[derive(Clone, Copy, Debug)]
+pub enum Co2Curve {
+    /// The same value for every sample
+    Constant(u16),
+    /// Linear change from `from` to `to` over `samples` samples, then `to` forever
+    Ramp {
+        from: u16,
+        to: u16,
+        samples: u32,
+    },
+    /// Cycle through the values, the slice must not be empty
+    Sequence(&'static [u16]),
+    /// Arbitrary function of the sample number
+    Function(fn(u32) -> u16),
+}
+
+impl Co2Curve {
+    /// Returns the CO2 concentration in ppm for sample number `n`
+    pub fn sample(&self, n: u32) -> u16 {
+        match *self {
+            Co2Curve::Constant(value) => value,
+            Co2Curve::Ramp { from, to, samples } => {
+                if n >= samples {
+                    return to;
+                }
+                let delta = (i64::from(to) - i64::from(from)) * i64::from(n) / i64::from(samples);
+                (i64::from(from) + delta) as u16
+            },
+            Co2Curve::Sequence(values) => values[n as usize % values.len()],
+            Co2Curve::Function(f) => f(n),
+        }
+    }
+}
+
+/// Emulated sensor
+///
+/// The emulated sensor takes a new sample from its [`Co2Curve`] on every CO2 concentration
+/// request. Zero and span calibration commands adjust the reported values the same way the
+/// real sensor does: the current sample becomes 400 ppm or the requested span respectively.
+#[derive(Clone, Debug)]
+pub struct SimulatedSensor {
+    curve: Co2Curve,
+    sample: u32,
+    temperature_c: i16,
+    status: u8,
+    min_light_adc: u16,
+    abc: bool,
+    detection_range: u32,
+    firmware_version: [u8; 4],
+    preheat: Option<u16>,
+    bootloader_frames: u32,
+
+    zero_sample: f32,
+    offset: f32,
+    gain: f32,
+    zero_calibrations: u32,
+    span_calibrations: u32,
+    resets: u32,
+
+    rx: [u8; FRAME_LENGTH],
+    rx_len: usize,
+    tx: [u8; TX_CAPACITY],
+    tx_head: usize,
+    tx_len: usize,
+}
+
+impl SimulatedSensor {
+    /// Create an emulated MH-Z19B with ABC enabled and a detection range of 5000 ppm
+    pub fn new(curve: Co2Curve) -> Self {
+        SimulatedSensor {
+            curve,
+            sample: 0,
+            temperature_c: 25,
+            status: 0,
+            min_light_adc: 15000,
+            abc: true,
+            detection_range: 5000,
+            firmware_version: *b"0443",
+            preheat: None,
+            bootloader_frames: 0,
+
+            zero_sample: ZERO_POINT,
+            offset: 0.0,
+            gain: 1.0,
+            zero_calibrations: 0,
+            span_calibrations: 0,
+            resets: 0,
+
+            rx: [0; FRAME_LENGTH],
+            rx_len: 0,
+            tx: [0; TX_CAPACITY],
+            tx_head: 0,
+            tx_len: 0,
+        }
+    }
+
+    /// Replace the CO2 curve and restart it from the first sample
+    pub fn set_curve(&mut self, curve: Co2Curve) {
+        self.curve = curve;
+        self.sample = 0;
+    }
+
+    /// Set the module temperature
+    pub fn set_temperature(&mut self, temperature_c: i16) {
+        self.temperature_c = temperature_c;
+    }
+
+    /// Set the status byte of the 0x86 response
+    pub fn set_status(&mut self, status: u8) {
+        self.status = status;
+    }
+
+    /// Set the minimum light ADC value of the 0x85 response
+    pub fn set_min_light_adc(&mut self, value: u16) {
+        self.min_light_adc = value;
+    }
+
+    /// Set the firmware version string
+    pub fn set_firmware_version(&mut self, version: [u8; 4]) {
+        self.firmware_version = version;
+    }
+
+    /// Set the detection range without sending a command
+    pub fn set_detection_range(&mut self, range: u32) {
+        self.detection_range = range;
+    }
+
+    /// Report `value` instead of the curve while preheating, or the curve if `None`
+    ///
+    /// MH-Z19B reports a placeholder value (e.g. 410 or 500 ppm) for about 3 minutes after
+    /// power-on. No samples are taken from the curve while preheating.
+    pub fn set_preheat(&mut self, value: Option<u16>) {
+        self.preheat = value;
+    }
+
+    /// Answer the next `frames` requests with a bootloader prompt instead of a response
+    ///
+    /// MH-Z19B prints the prompt on its first requests after power-on.
+    pub fn set_bootloader_frames(&mut self, frames: u32) {
+        self.bootloader_frames = frames;
+    }
+
+    /// Returns the number of samples taken so far
+    pub fn samples(&self) -> u32 {
+        self.sample
+    }
+
+    /// Returns `true` if Automatic Baseline Correction is enabled
+    pub fn automatic_baseline_correction(&self) -> bool {
+        self.abc
+    }
+
+    /// Returns the detection range in ppm
+    pub fn detection_range(&self) -> u32 {
+        self.detection_range
+    }
+
+    /// Returns the number of zero point calibrations performed
+    pub fn zero_calibrations(&self) -> u32 {
+        self.zero_calibrations
+    }
+
+    /// Returns the number of span point calibrations performed
+    pub fn span_calibrations(&self) -> u32 {
+        self.span_calibrations
+    }
+
+    /// Returns the number of resets performed
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// Returns the number of bytes queued for the driver
+    pub fn pending(&self) -> usize {
+        self.tx_len
+    }
+
+    /// Returns the CO2 concentration the sensor would report for the next sample, unclamped
+    pub fn current_co2(&self) -> u16 {
+        self.corrected(self.curve.sample(self.sample))
+    }
+
+    fn corrected(&self, value: u16) -> u16 {
+        let value = self.offset + self.gain * f32::from(value);
+        if value <= 0.0 {
+            0
+        } else if value >= f32::from(u16::MAX) {
+            u16::MAX
+        } else {
+            (value + 0.5) as u16
+        }
+    }
+
+    fn next_co2(&mut self) -> u16 {
+        if let Some(value) = self.preheat {
+            return value;
+        }
+        let value = self.current_co2();
+        self.sample = self.sample.wrapping_add(1);
+        value
+    }
+
+    fn calibrate_zero_point(&mut self) {
+        let value = f32::from(self.curve.sample(self.sample));
+        self.zero_sample = value;
+        self.offset = ZERO_POINT - self.gain * value;
+        self.zero_calibrations += 1;
+    }
+
+    fn calibrate_span_point(&mut self, span: u16) {
+        let value = f32::from(self.curve.sample(self.sample));
+        // Keep the zero point, adjust the slope
+        if value != self.zero_sample {
+            self.gain = (f32::from(span) - ZERO_POINT) / (value - self.zero_sample);
+            self.offset = ZERO_POINT - self.gain * self.zero_sample;
+        }
+        self.span_calibrations += 1;
+    }
+
+    fn handle(&mut self, request: Request) {
+        if self.bootloader_frames > 0 {
+            self.bootloader_frames -= 1;
+            for b in BOOTLOADER_PROMPT {
+                self.push_tx(*b);
+            }
+            return;
+        }
+
+        let command = request.command();
+        match request {
+            Request::ReadCo2Concentration => {
+                let range = self.detection_range.min(u16::MAX.into()) as u16;
+                let co2 = self.next_co2().min(range).to_be_bytes();
+                let temperature = (self.temperature_c + 40) as u8;
+                self.respond(command, &[co2[0], co2[1], temperature, self.status, 0, 0]);
+            },
+            Request::ReadUnlimitedCo2 => {
+                let co2 = self.next_co2().to_be_bytes();
+                let high = (8 + self.temperature_c.div_euclid(15)) as u8;
+                let low = (self.temperature_c.rem_euclid(15) * 17) as u8;
+                let adc = self.min_light_adc.to_be_bytes();
+                self.respond(command, &[high, low, co2[0], co2[1], adc[0], adc[1]]);
+            },
+            Request::SetAutomaticBaselineCorrection(enabled) => {
+                self.abc = enabled;
+                self.respond(command, &[]);
+            },
+            Request::CalibrateZeroPoint => {
+                self.calibrate_zero_point();
+                self.respond(command, &[]);
+            },
+            Request::CalibrateSpanPoint(span) => {
+                self.calibrate_span_point(span);
+                self.respond(command, &[]);
+            },
+            Request::SetDetectionRange(range) => {
+                self.detection_range = range;
+                self.respond(command, &[]);
+            },
+            Request::GetDetectionRange => {
+                let range = self.detection_range.to_be_bytes();
+                self.respond(command, &[range[0], range[1], range[2], range[3]]);
+            },
+            Request::Reset => {
+                // The sensor reboots without responding
+                self.resets += 1;
+            },
+            Request::GetAnalogBounds => {
+                let high = (self.detection_range.min(u16::MAX.into()) as u16).to_be_bytes();
+                self.respond(command, &[high[0], high[1], 0, 0]);
+            },
+            Request::GetFirmwareVersion => {
+                let version = self.firmware_version;
+                self.respond(command, &version);
+            },
+        }
+    }
+
+    fn respond(&mut self, command: Command, data: &[u8]) {
+        for b in Frame::response(command, data).as_bytes() {
+            self.push_tx(*b);
+        }
+    }
+
+    /// Queue a byte for the driver, bytes which don't fit are lost like on a real UART
+    fn push_tx(&mut self, byte: u8) {
+        if self.tx_len < TX_CAPACITY {
+            self.tx[(self.tx_head + self.tx_len) % TX_CAPACITY] = byte;
+            self.tx_len += 1;
+        }
+    }
+
+    fn pop_tx(&mut self) -> Option<u8> {
+        if self.tx_len == 0 {
+            return None;
+        }
+        let byte = self.tx[self.tx_head];
+        self.tx_head = (self.tx_head + 1) % TX_CAPACITY;
+        self.tx_len -= 1;
+        Some(byte)
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx_len == 0 && byte != START_BYTE {
+            return;
+        }
+        self.rx[self.rx_len] = byte;
+        self.rx_len += 1;
+        if self.rx_len < FRAME_LENGTH {
+            return;
+        }
+        self.rx_len = 0;
+
+        // Frames with the wrong checksum and unknown commands are ignored
+        if let Some(request) = Frame::from_bytes(self.rx).ok().as_ref().and_then(Request::decode) {
+            self.handle(request);
+        }
+    }
+}
+
+impl Read<u8> for SimulatedSensor {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.pop_tx().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write<u8> for SimulatedSensor {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        self.receive(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+impl embedded_io_async::ErrorType for SimulatedSensor {
+    type Error = Infallible;
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+impl embedded_io_async::Read for SimulatedSensor {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.tx_len == 0 {
+            // Nothing arrives until the next request, so only a timeout ends the wait
+            core::future::pending::<()>().await;
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match self.pop_tx() {
+                Some(byte) => buf[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+impl embedded_io_async::Write for SimulatedSensor {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        for b in buf {
+            self.receive(*b);
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Delay which completes immediately
+///
+/// Makes [`AsyncWinsenSensor`](crate::AsyncWinsenSensor) timeouts deterministic: a
+/// timeout expires as soon as the serial port has no data.
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoDelay;
+
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+impl embedded_hal_async::delay::DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// Monotonic counter which advances by one tick every time it is read
+///
+/// Makes driver timeouts deterministic: a timeout of `n` ticks expires after
+/// `n` polls of the serial port.
+#[derive(Debug)]
+pub struct StepCounter {
+    value: Cell<u32>,
+    frequency: u32,
+}
+
+impl StepCounter {
+    /// Create a counter running at `frequency` ticks per second
+    pub fn new(frequency: u32) -> Self {
+        StepCounter {
+            value: Cell::new(0),
+            frequency,
+        }
+    }
+}
+
+impl Default for StepCounter {
+    /// One tick per millisecond
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl MonotonicCounter for StepCounter {
+    fn value(&self) -> u32 {
+        let value = self.value.get();
+        self.value.set(value.wrapping_add(1));
+        value
+    }
+
+    fn frequency(&self) -> u32 {
+        self.frequency
+    }
+}
@@ -0,0 +1,341 @@
+//! SQLite-backed reading history.
+//!
+//! [`HistoryStore`] keeps recent readings at full resolution and downsamples older ones
+//! into min/avg/max buckets, so that long periods can be queried at a fixed cost.
+
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{Measurement, MonotonicCounter, Serial, WinsenSensor};
+
+/// How long data is kept at each resolution
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    /// Age after which readings are downsampled
+    pub raw: Duration,
+    /// Duration of a downsampled bucket
+    pub bucket: Duration,
+    /// Age after which downsampled buckets are deleted
+    pub downsampled: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            raw: Duration::from_secs(2 * 24 * 3600),
+            bucket: Duration::from_secs(5 * 60),
+            downsampled: Duration::from_secs(365 * 24 * 3600),
+        }
+    }
+}
+
+/// Single stored reading
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub timestamp: SystemTime,
+    pub co2_ppm: u16,
+    pub temperature_c: i16,
+}
+
+/// Aggregated readings of one query interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    /// Start of the interval
+    pub start: SystemTime,
+    pub co2_min: u16,
+    pub co2_avg: f64,
+    pub co2_max: u16,
+    pub temperature_avg: f64,
+    /// Number of readings aggregated into the bucket
+    pub count: u32,
+}
+
+fn to_unix(timestamp: SystemTime) -> i64 {
+    timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn from_unix(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+/// Reading history in an SQLite database
+pub struct HistoryStore {
+    connection: Connection,
+    retention: Retention,
+}
+
+impl HistoryStore {
+    /// Open or create a database file
+    pub fn open(path: impl AsRef<Path>, retention: Retention) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?, retention)
+    }
+
+    /// Create a database which is lost when the store is dropped
+    pub fn open_in_memory(retention: Retention) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, retention)
+    }
+
+    fn with_connection(connection: Connection, retention: Retention) -> rusqlite::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                timestamp INTEGER NOT NULL PRIMARY KEY,
+                co2_ppm INTEGER NOT NULL,
+                temperature_c INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS buckets (
+                start INTEGER NOT NULL PRIMARY KEY,
+                co2_min INTEGER NOT NULL,
+                co2_avg REAL NOT NULL,
+                co2_max INTEGER NOT NULL,
+                temperature_avg REAL NOT NULL,
+                count INTEGER NOT NULL
+            );",
+        )?;
+        Ok(HistoryStore {
+            connection,
+            retention,
+        })
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Store a reading, replacing one with the same timestamp (in seconds)
+    pub fn insert(&mut self, timestamp: SystemTime, measurement: &Measurement) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO readings (timestamp, co2_ppm, temperature_c) VALUES (?1, ?2, ?3)",
+            params![to_unix(timestamp), measurement.co2_ppm, measurement.temperature_c],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the most recent reading at full resolution
+    pub fn latest(&self) -> rusqlite::Result<Option<Sample>> {
+        self.connection.query_row(
+            "SELECT timestamp, co2_ppm, temperature_c FROM readings ORDER BY timestamp DESC LIMIT 1",
+            [],
+            |row| Ok(Sample {
+                timestamp: from_unix(row.get(0)?),
+                co2_ppm: row.get(1)?,
+                temperature_c: row.get(2)?,
+            }),
+        ).optional()
+    }
+
+    /// Downsample readings older than the raw retention and delete expired buckets
+    ///
+    /// Readings are moved into buckets only once their bucket is complete, a bucket that
+    /// already exists is merged with the new readings.
+    pub fn maintain(&mut self, now: SystemTime) -> rusqlite::Result<()> {
+        let bucket = self.retention.bucket.as_secs().max(1) as i64;
+        // Align the cutoff to a bucket boundary, so that no bucket is split
+        let raw_cutoff = (to_unix(now) - self.retention.raw.as_secs() as i64).div_euclid(bucket) * bucket;
+        let bucket_cutoff = to_unix(now) - self.retention.downsampled.as_secs() as i64;
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO buckets (start, co2_min, co2_avg, co2_max, temperature_avg, count)
+            SELECT (timestamp / ?1) * ?1 AS start, MIN(co2_ppm), AVG(co2_ppm), MAX(co2_ppm), AVG(temperature_c), COUNT(*)
+            FROM readings WHERE timestamp < ?2 GROUP BY start
+            ON CONFLICT (start) DO UPDATE SET
+                co2_min = MIN(co2_min, excluded.co2_min),
+                co2_avg = (co2_avg * count + excluded.co2_avg * excluded.count) / (count + excluded.count),
+                co2_max = MAX(co2_max, excluded.co2_max),
+                temperature_avg = (temperature_avg * count + excluded.temperature_avg * excluded.count) / (count + excluded.count),
+                count = count + excluded.count",
+            params![bucket, raw_cutoff],
+        )?;
+        transaction.execute("DELETE FROM readings WHERE timestamp < ?1", params![raw_cutoff])?;
+        transaction.execute("DELETE FROM buckets WHERE start < ?1", params![bucket_cutoff])?;
+        transaction.commit()
+    }
+
+    /// Aggregate readings in `from..to` into buckets of `resolution`
+    ///
+    /// Intervals are aligned to multiples of `resolution` since the Unix epoch and empty ones
+    /// are omitted. Downsampled buckets overlapping `from..to` are included whole, so the first
+    /// interval may contain readings from up to one [`Retention::bucket`] before `from`.
+    /// Downsampled data can't be returned at a resolution finer than [`Retention::bucket`],
+    /// such buckets are aligned to `resolution` instead.
+    pub fn query(&self, from: SystemTime, to: SystemTime, resolution: Duration) -> rusqlite::Result<Vec<Bucket>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT (start / ?1) * ?1 AS interval, MIN(co2_min), SUM(co2_avg * count) / SUM(count), MAX(co2_max),
+                SUM(temperature_avg * count) / SUM(count), SUM(count)
+            FROM (
+                SELECT timestamp AS start, co2_ppm AS co2_min, CAST(co2_ppm AS REAL) AS co2_avg, co2_ppm AS co2_max,
+                    CAST(temperature_c AS REAL) AS temperature_avg, 1 AS count
+                FROM readings WHERE timestamp >= ?2 AND timestamp < ?3
+                UNION ALL
+                SELECT start, co2_min, co2_avg, co2_max, temperature_avg, count
+                FROM buckets WHERE start > ?2 - ?4 AND start < ?3
+            )
+            GROUP BY interval ORDER BY interval",
+        )?;
+        let resolution = resolution.as_secs().max(1) as i64;
+        let bucket = self.retention.bucket.as_secs().max(1) as i64;
+        let rows = statement.query_map(params![resolution, to_unix(from), to_unix(to), bucket], |row| {
+            Ok(Bucket {
+                start: from_unix(row.get(0)?),
+                co2_min: row.get(1)?,
+                co2_avg: row.get(2)?,
+                co2_max: row.get(3)?,
+                temperature_avg: row.get(4)?,
+                count: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Aggregate the last `period` into buckets of `resolution`, e.g. the last 24 hours at 5 minutes
+    ///
+    /// A period reaching before the Unix epoch returns everything.
+    pub fn query_last(&self, period: Duration, resolution: Duration) -> rusqlite::Result<Vec<Bucket>> {
+        let now = SystemTime::now();
+        let from = now.checked_sub(period).filter(|&from| from > UNIX_EPOCH).unwrap_or(UNIX_EPOCH);
+        self.query(from, now + Duration::from_secs(1), resolution)
+    }
+
+    /// Sample `sensor` every `interval` and keep the database maintained
+    ///
+    /// Failed readings are skipped. Returns on a database error only.
+    pub fn run<S: Serial, C: MonotonicCounter>(&mut self, sensor: &mut WinsenSensor<S, C>, interval: Duration) -> rusqlite::Result<()> {
+        let mut last_maintenance: Option<Instant> = None;
+        loop {
+            let started = Instant::now();
+            if let Ok(measurement) = sensor.read_measurement() {
+                self.insert(SystemTime::now(), &measurement)?;
+            }
+            if last_maintenance.is_none_or(|t| t.elapsed() >= self.retention.bucket) {
+                self.maintain(SystemTime::now())?;
+                last_maintenance = Some(started);
+            }
+            sleep(interval.saturating_sub(started.elapsed()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn measurement(co2_ppm: u16, temperature_c: i16) -> Measurement {
+        Measurement {
+            co2_ppm,
+            temperature_c,
+            status: 0,
+            reserved: [0; 2],
+        }
+    }
+
+    /// Raw readings for an hour, 5 minute buckets for a day
+    fn store() -> HistoryStore {
+        HistoryStore::open_in_memory(Retention {
+            raw: Duration::from_secs(3600),
+            bucket: Duration::from_secs(300),
+            downsampled: Duration::from_secs(24 * 3600),
+        }).unwrap()
+    }
+
+    fn count(store: &HistoryStore, table: &str) -> u32 {
+        store.connection().query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    fn buckets(store: &HistoryStore) -> Vec<(i64, u16, f64, u16, f64, u32)> {
+        let mut statement = store.connection()
+            .prepare("SELECT start, co2_min, co2_avg, co2_max, temperature_avg, count FROM buckets ORDER BY start")
+            .unwrap();
+        let rows = statement.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        }).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn maintain_downsamples_complete_buckets() {
+        let mut store = store();
+        for &(t, co2, temperature) in &[(0, 400, 20), (60, 500, 22), (120, 600, 24), (300, 700, 25), (4000, 800, 26)] {
+            store.insert(at(t), &measurement(co2, temperature)).unwrap();
+        }
+        // The cutoff at 310 s is aligned down to the bucket starting at 300 s
+        store.maintain(at(3910)).unwrap();
+        assert_eq!(buckets(&store), vec![(0, 400, 500.0, 600, 22.0, 3)]);
+        assert_eq!(count(&store, "readings"), 2);
+        assert_eq!(store.latest().unwrap(), Some(Sample { timestamp: at(4000), co2_ppm: 800, temperature_c: 26 }));
+    }
+
+    #[test]
+    fn maintain_merges_into_existing_buckets() {
+        let mut store = store();
+        for &(t, co2) in &[(0, 400), (60, 500), (120, 600)] {
+            store.insert(at(t), &measurement(co2, 22)).unwrap();
+        }
+        store.maintain(at(3910)).unwrap();
+        // A late reading for an already downsampled bucket
+        store.insert(at(240), &measurement(800, 30)).unwrap();
+        store.maintain(at(3910)).unwrap();
+        assert_eq!(buckets(&store), vec![(0, 400, 575.0, 800, 24.0, 4)]);
+        assert_eq!(count(&store, "readings"), 0);
+    }
+
+    #[test]
+    fn maintain_deletes_expired_buckets() {
+        let mut store = store();
+        for &t in &[0, 300, 4000] {
+            store.insert(at(t), &measurement(400, 20)).unwrap();
+        }
+        store.maintain(at(3910)).unwrap();
+        assert_eq!(count(&store, "buckets"), 1);
+
+        store.maintain(at(24 * 3600 + 301)).unwrap();
+        let starts: Vec<i64> = buckets(&store).iter().map(|b| b.0).collect();
+        assert_eq!(starts, vec![3900]);
+        assert_eq!(count(&store, "readings"), 0);
+    }
+
+    /// Bucket 0..300 s downsampled from 400 and 600 ppm, raw readings at 300, 599 and 600 s
+    fn store_with_history() -> HistoryStore {
+        let mut store = store();
+        for &(t, co2) in &[(0, 400), (290, 600), (300, 800), (599, 1000), (600, 1200)] {
+            store.insert(at(t), &measurement(co2, 20)).unwrap();
+        }
+        store.maintain(at(3900)).unwrap();
+        assert_eq!(count(&store, "buckets"), 1);
+        store
+    }
+
+    fn query(store: &HistoryStore, from: u64, to: u64, resolution: u64) -> Vec<(i64, u16, f64, u16, u32)> {
+        store.query(at(from), at(to), Duration::from_secs(resolution)).unwrap().iter()
+            .map(|b| (to_unix(b.start), b.co2_min, b.co2_avg, b.co2_max, b.count))
+            .collect()
+    }
+
+    #[test]
+    fn query_combines_buckets_and_readings() {
+        let store = store_with_history();
+        assert_eq!(query(&store, 0, 600, 300), vec![(0, 400, 500.0, 600, 2), (300, 800, 900.0, 1000, 2)]);
+        assert_eq!(query(&store, 0, 601, 600), vec![(0, 400, 700.0, 1000, 4), (600, 1200, 1200.0, 1200, 1)]);
+        assert_eq!(query(&store, 0, 300, 300), vec![(0, 400, 500.0, 600, 2)]);
+    }
+
+    #[test]
+    fn query_includes_buckets_overlapping_the_start() {
+        let store = store_with_history();
+        // The bucket starting before `from` is included whole
+        assert_eq!(query(&store, 100, 600, 300), vec![(0, 400, 500.0, 600, 2), (300, 800, 900.0, 1000, 2)]);
+        assert_eq!(query(&store, 299, 300, 300), vec![(0, 400, 500.0, 600, 2)]);
+        // The bucket ending at `from` is not
+        assert_eq!(query(&store, 300, 600, 300), vec![(300, 800, 900.0, 1000, 2)]);
+    }
+}
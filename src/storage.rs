@@ -0,0 +1,494 @@
+//! Persistent sensor settings on flash or EEPROM.
+//!
+//! [`StoredConfig`] is serialised to a fixed-size record with a magic number, a version
+//! and a CRC-32, and kept at an offset of any `embedded-storage` device by [`ConfigStore`].
+//! [`CorrectedSensor::from_storage`] applies it when constructing the sensor wrapper,
+//! [`Calibrator::restore_calibration_times`] and [`Calibrator::save_calibration_times`]
+//! load and save the calibration timestamps.
+
+use core::convert::TryFrom;
+use core::fmt;
+
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::calibration::Calibrator;
+use crate::correction::{Correction, CorrectedSensor};
+use crate::{Concentration, Error, MonotonicCounter, Serial, WinsenSensor};
+
+/// Size of a serialised record in bytes
+pub const RECORD_SIZE: usize = 32;
+
+/// Current record format version
+pub const RECORD_VERSION: u8 = 1;
+
+const MAGIC: [u8; 4] = *b"WNSN";
+
+/// Timestamp value meaning "never"
+const NO_TIMESTAMP: u32 = u32::MAX;
+
+/// Settings surviving reboots
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoredConfig {
+    /// Linear correction of readings
+    pub correction: Correction,
+    /// Automatic Baseline Correction state to set, `None` to leave the sensor default
+    pub abc: Option<bool>,
+    /// Detection range to set, `None` to leave the sensor setting
+    pub detection_range: Option<Concentration>,
+    /// Time of the last zero point calibration, in application defined units (e.g. RTC seconds)
+    ///
+    /// The sensor has no clock, the application passes the current time in the same units
+    /// along with their length in milliseconds to the [`Calibrator`] methods using it.
+    pub zero_calibrated_at: Option<u32>,
+    /// Time of the last span point calibration, in application defined units
+    pub span_calibrated_at: Option<u32>,
+}
+
+/// Invalid record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    /// Nothing was stored, the storage is erased
+    Empty,
+    /// The storage holds something else
+    WrongMagic,
+    /// The record was written by a newer version
+    UnsupportedVersion(u8),
+    WrongChecksum,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Empty => f.write_str("no config stored"),
+            RecordError::WrongMagic => f.write_str("not a config record"),
+            RecordError::UnsupportedVersion(version) => write!(f, "unsupported config version {}", version),
+            RecordError::WrongChecksum => f.write_str("wrong config checksum"),
+        }
+    }
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+impl StoredConfig {
+    /// Serialise the config
+    ///
+    /// Layout (little endian): magic `WNSN`, version, ABC state (0 off, 1 on, 0xff unset),
+    /// 2 reserved bytes, offset and gain as `f32`, detection range (0 if unset), zero and
+    /// span calibration timestamps (0xffffffff if unset) and a CRC-32 of the preceding bytes.
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = RECORD_VERSION;
+        bytes[5] = match self.abc {
+            Some(enabled) => u8::from(enabled),
+            None => 0xff,
+        };
+        bytes[8..12].copy_from_slice(&self.correction.offset.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.correction.gain.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.detection_range.map_or(0, Concentration::ppm).to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.zero_calibrated_at.unwrap_or(NO_TIMESTAMP).to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.span_calibrated_at.unwrap_or(NO_TIMESTAMP).to_le_bytes());
+        let crc = crc32(&bytes[..RECORD_SIZE - 4]);
+        bytes[RECORD_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialise and validate a record
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Result<Self, RecordError> {
+        if bytes.iter().all(|&b| b == 0xff) || bytes.iter().all(|&b| b == 0) {
+            return Err(RecordError::Empty);
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(RecordError::WrongMagic);
+        }
+        if bytes[4] != RECORD_VERSION {
+            return Err(RecordError::UnsupportedVersion(bytes[4]));
+        }
+        if crc32(&bytes[..RECORD_SIZE - 4]) != u32_at(bytes, RECORD_SIZE - 4) {
+            return Err(RecordError::WrongChecksum);
+        }
+
+        let timestamp = |offset| Some(u32_at(bytes, offset)).filter(|&t| t != NO_TIMESTAMP);
+        Ok(StoredConfig {
+            correction: Correction {
+                offset: f32::from_bits(u32_at(bytes, 8)),
+                gain: f32::from_bits(u32_at(bytes, 12)),
+            },
+            abc: match bytes[5] {
+                0 => Some(false),
+                1 => Some(true),
+                _ => None,
+            },
+            detection_range: Some(u32_at(bytes, 16)).filter(|&range| range != 0).map(Concentration::from_ppm),
+            zero_calibrated_at: timestamp(20),
+            span_calibrated_at: timestamp(24),
+        })
+    }
+}
+
+/// Storage or record error
+#[derive(Debug, PartialEq)]
+pub enum StorageError<E> {
+    Storage(E),
+    Record(RecordError),
+}
+
+impl<E: fmt::Debug> fmt::Display for StorageError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Storage(e) => write!(f, "storage error: {:?}", e),
+            StorageError::Record(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug> std::error::Error for StorageError<E> {}
+
+/// Config record at a fixed offset of a storage device
+pub struct ConfigStore<T> {
+    storage: T,
+    offset: u32,
+}
+
+impl<T> ConfigStore<T> {
+    /// Keep the record at `offset`, [`RECORD_SIZE`] bytes must be available there
+    pub fn new(storage: T, offset: u32) -> Self {
+        ConfigStore {
+            storage,
+            offset,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.storage
+    }
+}
+
+impl<T: ReadStorage> ConfigStore<T> {
+    /// Read and validate the stored config
+    pub fn load(&mut self) -> Result<StoredConfig, StorageError<T::Error>> {
+        let mut bytes = [0; RECORD_SIZE];
+        self.storage.read(self.offset, &mut bytes).map_err(StorageError::Storage)?;
+        StoredConfig::from_bytes(&bytes).map_err(StorageError::Record)
+    }
+
+    /// Read the stored config, falling back to the default one if there is no valid record
+    pub fn load_or_default(&mut self) -> Result<StoredConfig, T::Error> {
+        match self.load() {
+            Ok(config) => Ok(config),
+            Err(StorageError::Storage(e)) => Err(e),
+            Err(StorageError::Record(_)) => Ok(StoredConfig::default()),
+        }
+    }
+}
+
+impl<T: Storage> ConfigStore<T> {
+    pub fn save(&mut self, config: &StoredConfig) -> Result<(), T::Error> {
+        self.storage.write(self.offset, &config.to_bytes())
+    }
+}
+
+/// Failure to apply a stored config
+#[derive(Debug, PartialEq)]
+pub enum ApplyError<E, SE> {
+    /// Sending a setting to the sensor failed
+    Sensor(Error<E>),
+    /// Reading the storage failed
+    Storage(SE),
+}
+
+impl<E: fmt::Debug, SE: fmt::Debug> fmt::Display for ApplyError<E, SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::Sensor(e) => write!(f, "{}", e),
+            ApplyError::Storage(e) => write!(f, "storage error: {:?}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug, SE: fmt::Debug> std::error::Error for ApplyError<E, SE> {}
+
+impl<S: Serial, C: MonotonicCounter> WinsenSensor<S, C> {
+    /// Send the ABC state and the detection range of `config` to the sensor, if set
+    pub fn apply_stored_config(&mut self, config: &StoredConfig) -> Result<(), Error<S::Error>> {
+        if let Some(enabled) = config.abc {
+            self.set_automatic_baseline_correction(enabled)?;
+        }
+        if let Some(range) = config.detection_range {
+            self.set_detection_range(range)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: Serial, C: MonotonicCounter> CorrectedSensor<'a, WinsenSensor<S, C>> {
+    /// Load the config from `store`, apply it to the sensor and use its correction
+    ///
+    /// The default config is used if no valid record is stored. Returns the applied config too.
+    pub fn from_storage<T: ReadStorage>(
+        mut sensor: WinsenSensor<S, C>,
+        store: &mut ConfigStore<T>,
+    ) -> Result<(Self, StoredConfig), ApplyError<S::Error, T::Error>> {
+        let config = store.load_or_default().map_err(ApplyError::Storage)?;
+        sensor.apply_stored_config(&config).map_err(ApplyError::Sensor)?;
+        Ok((CorrectedSensor::new(sensor, config.correction), config))
+    }
+}
+
+/// Age in milliseconds of a calibration done at `at`, `None` if it's in the future or
+/// too old to be tracked
+fn age_ms(at: u32, now: u32, ms_per_unit: u32) -> Option<u32> {
+    let age = u64::from(now.checked_sub(at)?) * u64::from(ms_per_unit);
+    u32::try_from(age).ok()
+}
+
+impl<S: Serial, C: MonotonicCounter> Calibrator<S, C> {
+    /// Restore the calibration times stored in `config`
+    ///
+    /// `now` is the current time in the units of the stored timestamps and `ms_per_unit`
+    /// their length, e.g. 1000 for RTC seconds. A calibration done at `zero_calibrated_at`
+    /// is recorded with [`set_zero_calibrated_ms_ago`](Calibrator::set_zero_calibrated_ms_ago)
+    /// of `(now - zero_calibrated_at) * ms_per_unit`. Timestamps in the future or older than
+    /// `u32::MAX` milliseconds or the counter range are ignored, a zero point calibration is
+    /// then required before a span point one.
+    pub fn restore_calibration_times(&mut self, config: &StoredConfig, now: u32, ms_per_unit: u32) {
+        if let Some(age) = config.zero_calibrated_at.and_then(|at| age_ms(at, now, ms_per_unit)) {
+            self.set_zero_calibrated_ms_ago(age);
+        }
+        if let Some(age) = config.span_calibrated_at.and_then(|at| age_ms(at, now, ms_per_unit)) {
+            self.set_span_calibrated_ms_ago(age);
+        }
+    }
+
+    /// Write the times of the calibrations known to the calibrator to `config`
+    ///
+    /// Timestamps of calibrations the calibrator doesn't know about are kept.
+    pub fn record_calibration_times(&self, config: &mut StoredConfig, now: u32, ms_per_unit: u32) {
+        let at = |age: u32| now.wrapping_sub(age / ms_per_unit.max(1));
+        if let Some(age) = self.zero_calibration_age_ms() {
+            config.zero_calibrated_at = Some(at(age));
+        }
+        if let Some(age) = self.span_calibration_age_ms() {
+            config.span_calibrated_at = Some(at(age));
+        }
+    }
+
+    /// Save the calibration times to `store`, keeping the other stored settings
+    ///
+    /// Returns the saved config.
+    pub fn save_calibration_times<T: Storage>(
+        &self,
+        store: &mut ConfigStore<T>,
+        now: u32,
+        ms_per_unit: u32,
+    ) -> Result<StoredConfig, T::Error> {
+        let mut config = store.load_or_default()?;
+        self.record_calibration_times(&mut config, now, ms_per_unit);
+        store.save(&config)?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Erased in-memory storage
+    struct RamStorage([u8; 64]);
+
+    impl RamStorage {
+        fn new() -> Self {
+            RamStorage([0xff; 64])
+        }
+    }
+
+    impl ReadStorage for RamStorage {
+        type Error = ();
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+            let start = offset as usize;
+            bytes.copy_from_slice(self.0.get(start..start + bytes.len()).ok_or(())?);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl Storage for RamStorage {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+            let start = offset as usize;
+            self.0.get_mut(start..start + bytes.len()).ok_or(())?.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn config() -> StoredConfig {
+        StoredConfig {
+            correction: Correction::new(-12.5, 1.02),
+            abc: Some(false),
+            detection_range: Some(Concentration::from_ppm(2000)),
+            zero_calibrated_at: Some(1_700_000_000),
+            span_calibrated_at: None,
+        }
+    }
+
+    #[test]
+    fn record_round_trip() {
+        assert_eq!(StoredConfig::from_bytes(&config().to_bytes()), Ok(config()));
+        let default = StoredConfig::default();
+        assert_eq!(StoredConfig::from_bytes(&default.to_bytes()), Ok(default));
+    }
+
+    #[test]
+    fn erased_record_is_empty() {
+        assert_eq!(StoredConfig::from_bytes(&[0xff; RECORD_SIZE]), Err(RecordError::Empty));
+        assert_eq!(StoredConfig::from_bytes(&[0; RECORD_SIZE]), Err(RecordError::Empty));
+    }
+
+    #[test]
+    fn corrupted_record_fails_checksum() {
+        for i in 5..RECORD_SIZE {
+            let mut bytes = config().to_bytes();
+            bytes[i] ^= 0x01;
+            assert_eq!(StoredConfig::from_bytes(&bytes), Err(RecordError::WrongChecksum), "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn foreign_or_newer_records_are_rejected() {
+        let mut bytes = config().to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(StoredConfig::from_bytes(&bytes), Err(RecordError::WrongMagic));
+
+        let mut bytes = config().to_bytes();
+        bytes[4] = RECORD_VERSION + 1;
+        assert_eq!(StoredConfig::from_bytes(&bytes), Err(RecordError::UnsupportedVersion(RECORD_VERSION + 1)));
+    }
+
+    #[test]
+    fn config_store_load_and_save() {
+        let mut store = ConfigStore::new(RamStorage::new(), 16);
+        assert_eq!(store.load(), Err(StorageError::Record(RecordError::Empty)));
+        assert_eq!(store.load_or_default(), Ok(StoredConfig::default()));
+
+        store.save(&config()).unwrap();
+        assert_eq!(store.load(), Ok(config()));
+        let storage = store.into_inner();
+        assert!(storage.0[..16].iter().all(|&b| b == 0xff));
+        assert!(storage.0[16 + RECORD_SIZE..].iter().all(|&b| b == 0xff));
+
+        // The record doesn't fit at the end of the storage
+        let mut store = ConfigStore::new(storage, 48);
+        assert_eq!(store.load(), Err(StorageError::Storage(())));
+        assert_eq!(store.save(&config()), Err(()));
+    }
+
+    #[cfg(feature = "sim")]
+    mod sensor {
+        use super::*;
+        use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+        use crate::Co2Sensor;
+
+        fn sensor() -> WinsenSensor<SimulatedSensor, StepCounter> {
+            WinsenSensor::new(SimulatedSensor::new(Co2Curve::Constant(800)), StepCounter::default())
+        }
+
+        #[test]
+        fn corrected_sensor_from_storage() {
+            let mut store = ConfigStore::new(RamStorage::new(), 0);
+            let config = StoredConfig {
+                correction: Correction::new(10.0, 1.0),
+                ..config()
+            };
+            store.save(&config).unwrap();
+
+            let (mut sensor, applied) = CorrectedSensor::from_storage(sensor(), &mut store).unwrap();
+            assert_eq!(applied, config);
+            assert!(!sensor.sensor_mut().serial.automatic_baseline_correction());
+            assert_eq!(sensor.sensor_mut().serial.detection_range(), 2000);
+            assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(810)));
+        }
+
+        #[test]
+        fn corrected_sensor_from_erased_storage() {
+            let mut store = ConfigStore::new(RamStorage::new(), 0);
+            let (mut sensor, applied) = CorrectedSensor::from_storage(sensor(), &mut store).unwrap();
+            assert_eq!(applied, StoredConfig::default());
+            assert!(sensor.sensor_mut().serial.automatic_baseline_correction());
+            assert_eq!(sensor.read_co2_concentration(), Ok(Concentration::from_ppm(800)));
+        }
+
+        fn calibrator() -> Calibrator<SimulatedSensor, StepCounter> {
+            calibrator_with(StepCounter::default())
+        }
+
+        fn calibrator_with(counter: StepCounter) -> Calibrator<SimulatedSensor, StepCounter> {
+            Calibrator::new(WinsenSensor::new(SimulatedSensor::new(Co2Curve::Constant(400)), counter))
+        }
+
+        #[test]
+        fn calibration_times_round_trip() {
+            let mut calibrator = calibrator();
+            let mut config = StoredConfig {
+                zero_calibrated_at: Some(1000),
+                ..Default::default()
+            };
+            calibrator.restore_calibration_times(&config, 1600, 1000);
+            assert!(calibrator.zero_calibration_age_ms().unwrap() >= 600_000);
+            assert_eq!(calibrator.span_calibration_age_ms(), None);
+
+            calibrator.set_span_calibrated_ms_ago(0);
+            calibrator.record_calibration_times(&mut config, 1600, 1000);
+            assert_eq!(config.zero_calibrated_at, Some(1000));
+            assert_eq!(config.span_calibrated_at, Some(1600));
+        }
+
+        #[test]
+        fn future_calibration_times_are_ignored() {
+            let mut calibrator = calibrator();
+            let config = StoredConfig {
+                zero_calibrated_at: Some(2000),
+                ..Default::default()
+            };
+            calibrator.restore_calibration_times(&config, 1600, 1000);
+            assert_eq!(calibrator.zero_calibration_age_ms(), None);
+        }
+
+        #[test]
+        fn calibration_times_beyond_the_counter_range_are_ignored() {
+            // A 1 MHz counter wraps after 71 minutes
+            let mut calibrator = calibrator_with(StepCounter::new(1_000_000));
+            calibrator.set_zero_calibrated_ms_ago(7_200_000);
+            assert_eq!(calibrator.zero_calibration_age_ms(), None);
+
+            let config = StoredConfig {
+                zero_calibrated_at: Some(1000),
+                span_calibrated_at: Some(8000),
+                ..Default::default()
+            };
+            calibrator.restore_calibration_times(&config, 8200, 1000);
+            assert_eq!(calibrator.zero_calibration_age_ms(), None);
+            assert!(calibrator.span_calibration_age_ms().unwrap() >= 200_000);
+        }
+    }
+}
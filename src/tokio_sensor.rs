@@ -0,0 +1,51 @@
+//! Tokio integration for [`AsyncWinsenSensor`].
+//!
+//! Any `tokio::io::AsyncRead + AsyncWrite` port (e.g. `tokio_serial::SerialStream`)
+//! can be used with the same command set as [`WinsenSensor`](crate::WinsenSensor).
+
+use std::io;
+use std::time::Duration;
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_adapters::tokio_1::FromTokio;
+use futures_util::stream::{self, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{AsyncWinsenSensor, Error, Measurement};
+
+/// [`AsyncWinsenSensor`] running on top of a tokio I/O object
+pub type TokioWinsenSensor<T> = AsyncWinsenSensor<FromTokio<T>, TokioDelay>;
+
+/// `DelayNs` implementation backed by `tokio::time::sleep`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioDelay;
+
+impl DelayNs for TokioDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        tokio::time::sleep(Duration::from_nanos(ns.into())).await
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWinsenSensor<FromTokio<T>, TokioDelay> {
+    /// Create a sensor driver from a tokio I/O object
+    pub fn from_tokio(port: T) -> Self {
+        Self::new(FromTokio::new(port), TokioDelay)
+    }
+
+    /// Returns a stream of measurements taken every `period`
+    ///
+    /// The first measurement is taken immediately. Errors are yielded as stream items,
+    /// so a single failed request doesn't terminate the stream. A zero `period` is raised to
+    /// 1 ms, the sensor can't answer faster anyway.
+    pub fn measurements(&mut self, period: Duration) -> impl Stream<Item = Result<Measurement, Error<io::Error>>> + '_ {
+        let mut ticker = interval(period.max(Duration::from_millis(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        stream::unfold((self, ticker), |(sensor, mut ticker)| async move {
+            ticker.tick().await;
+            let measurement = sensor.read_measurement().await;
+            Some((measurement, (sensor, ticker)))
+        })
+    }
+}
@@ -0,0 +1,304 @@
+//! Preheat detection.
+//!
+//! After power-on MH-Z19B reports placeholder values (410 or 500 ppm, depending on the
+//! firmware) for about 3 minutes. [`WarmupTracker`] tells those readings apart from real
+//! ones using the time since power-on, the known placeholder values and optionally the
+//! status byte.
+
+use crate::{elapsed_ms, ticks, Concentration, Error, Measurement, MonotonicCounter, Serial, WinsenSensor};
+
+/// Values reported instead of the CO2 concentration while preheating
+pub const PREHEAT_SENTINELS: &[Concentration] = &[Concentration::from_ppm(410), Concentration::from_ppm(500)];
+
+/// Sensor lifecycle phase
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Readings are placeholders
+    Warming,
+    /// Readings are real measurements
+    Valid,
+}
+
+/// Measurement with the phase it was taken in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reading {
+    pub measurement: Measurement,
+    pub phase: Phase,
+}
+
+/// Preheat detection parameters
+///
+/// Readings taken before `preheat_ms` has elapsed are always [`Phase::Warming`]. After that
+/// the sensor is ready with the first reading which is neither a sentinel value nor carries
+/// the `warming_status`, or once `max_preheat_ms` has elapsed, since a sentinel value may
+/// be a real concentration as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WarmupConfig {
+    /// Minimum preheat time
+    pub preheat_ms: u32,
+    /// Time after which readings are valid regardless of their values
+    pub max_preheat_ms: u32,
+    /// Placeholder values reported while preheating
+    pub sentinels: &'static [Concentration],
+    /// Status byte reported while preheating, if the sensor reports one
+    pub warming_status: Option<u8>,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        WarmupConfig {
+            preheat_ms: 180_000,
+            max_preheat_ms: 300_000,
+            sentinels: PREHEAT_SENTINELS,
+            warming_status: None,
+        }
+    }
+}
+
+impl WarmupConfig {
+    /// Set the minimum preheat time
+    pub fn preheat_ms(mut self, ms: u32) -> Self {
+        self.preheat_ms = ms;
+        self
+    }
+
+    /// Set the time after which readings are valid regardless of their values
+    pub fn max_preheat_ms(mut self, ms: u32) -> Self {
+        self.max_preheat_ms = ms;
+        self
+    }
+
+    /// Set the placeholder values
+    pub fn sentinels(mut self, sentinels: &'static [Concentration]) -> Self {
+        self.sentinels = sentinels;
+        self
+    }
+
+    /// Set the status byte reported while preheating
+    pub fn warming_status(mut self, status: Option<u8>) -> Self {
+        self.warming_status = status;
+        self
+    }
+}
+
+/// [`WinsenSensor`] wrapper flagging readings taken while preheating
+///
+/// Elapsed time is measured with the sensor's [`MonotonicCounter`] from the tracker
+/// construction, which is assumed to be the power-on time. The counter must not wrap
+/// around during `max_preheat_ms`.
+pub struct WarmupTracker<S, C> {
+    sensor: WinsenSensor<S, C>,
+    config: WarmupConfig,
+    /// Counter value at power-on
+    started: u32,
+    phase: Phase,
+}
+
+impl<S: Serial, C: MonotonicCounter> WarmupTracker<S, C> {
+    pub fn new(sensor: WinsenSensor<S, C>) -> Self {
+        Self::with_config(sensor, WarmupConfig::default())
+    }
+
+    pub fn with_config(sensor: WinsenSensor<S, C>, config: WarmupConfig) -> Self {
+        let started = sensor.counter.value();
+        WarmupTracker {
+            sensor,
+            config,
+            started,
+            phase: Phase::Warming,
+        }
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut WinsenSensor<S, C> {
+        &mut self.sensor
+    }
+
+    pub fn free(self) -> WinsenSensor<S, C> {
+        self.sensor
+    }
+
+    /// Account for the sensor being powered on `ms` milliseconds before the tracker was created
+    pub fn set_powered_for_ms(&mut self, ms: u32) {
+        self.started = self.sensor.counter.value().wrapping_sub(ticks(&self.sensor.counter, ms));
+    }
+
+    /// Skip preheat detection, e.g. after a restart of the host only
+    pub fn mark_ready(&mut self) {
+        self.phase = Phase::Valid;
+    }
+
+    /// Returns milliseconds elapsed since power-on
+    pub fn elapsed_ms(&self) -> u32 {
+        elapsed_ms(&self.sensor.counter, self.started)
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.phase == Phase::Valid
+    }
+
+    /// Update the phase with a new measurement
+    ///
+    /// Once the sensor is ready, the phase doesn't change any more.
+    pub fn classify(&mut self, measurement: &Measurement) -> Phase {
+        if self.phase == Phase::Warming {
+            let elapsed = self.elapsed_ms();
+            let placeholder = self.config.sentinels.contains(&measurement.co2())
+                || self.config.warming_status == Some(measurement.status);
+            if elapsed >= self.config.max_preheat_ms || (elapsed >= self.config.preheat_ms && !placeholder) {
+                self.phase = Phase::Valid;
+            }
+        }
+        self.phase
+    }
+
+    /// Read a measurement and flag it with the phase
+    pub fn read(&mut self) -> Result<Reading, Error<S::Error>> {
+        let measurement = self.sensor.read_measurement()?;
+        let phase = self.classify(&measurement);
+        Ok(Reading {
+            measurement,
+            phase,
+        })
+    }
+
+    /// Read a measurement, `WouldBlock` while the sensor is preheating
+    pub fn poll_ready(&mut self) -> nb::Result<Measurement, Error<S::Error>> {
+        match self.read()? {
+            Reading { measurement, phase: Phase::Valid } => Ok(measurement),
+            Reading { phase: Phase::Warming, .. } => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Block until the sensor is ready, polling every `poll_interval_ms`
+    ///
+    /// `delay` is called with the time to wait between polls in milliseconds, e.g.
+    /// `|ms| std::thread::sleep(Duration::from_millis(ms.into()))` or an `embedded-hal` delay.
+    /// Communication errors are ignored until `max_preheat_ms` has elapsed, as the sensor
+    /// may not answer properly right after power-on.
+    pub fn wait_ready(&mut self, poll_interval_ms: u32, mut delay: impl FnMut(u32)) -> Result<Measurement, Error<S::Error>> {
+        loop {
+            match self.poll_ready() {
+                Ok(measurement) => return Ok(measurement),
+                Err(nb::Error::Other(e)) => {
+                    if self.elapsed_ms() >= self.config.max_preheat_ms {
+                        return Err(e);
+                    }
+                },
+                Err(nb::Error::WouldBlock) => {},
+            }
+            delay(poll_interval_ms);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Co2Curve, SimulatedSensor, StepCounter};
+
+    fn tracker(config: WarmupConfig) -> WarmupTracker<SimulatedSensor, StepCounter> {
+        let sensor = WinsenSensor::new(SimulatedSensor::new(Co2Curve::Constant(800)), StepCounter::default());
+        WarmupTracker::with_config(sensor, config)
+    }
+
+    fn phase(tracker: &mut WarmupTracker<SimulatedSensor, StepCounter>) -> Phase {
+        tracker.read().unwrap().phase
+    }
+
+    #[test]
+    fn warming_until_preheat_time() {
+        let mut tracker = tracker(WarmupConfig::default());
+        assert_eq!(phase(&mut tracker), Phase::Warming);
+        assert_eq!(tracker.poll_ready(), Err(nb::Error::WouldBlock));
+
+        tracker.set_powered_for_ms(180_000);
+        assert_eq!(phase(&mut tracker), Phase::Valid);
+        assert!(tracker.is_ready());
+    }
+
+    #[test]
+    fn sentinels_keep_warming_until_max_preheat_time() {
+        let mut tracker = tracker(WarmupConfig::default());
+        tracker.sensor_mut().serial.set_preheat(Some(500));
+        tracker.set_powered_for_ms(200_000);
+        assert_eq!(phase(&mut tracker), Phase::Warming);
+
+        tracker.set_powered_for_ms(300_000);
+        let reading = tracker.read().unwrap();
+        assert_eq!(reading.phase, Phase::Valid);
+        assert_eq!(reading.measurement.co2_ppm, 500);
+    }
+
+    #[test]
+    fn ready_once_readings_are_real() {
+        let mut tracker = tracker(WarmupConfig::default());
+        tracker.sensor_mut().serial.set_preheat(Some(410));
+        tracker.set_powered_for_ms(200_000);
+        assert_eq!(phase(&mut tracker), Phase::Warming);
+
+        tracker.sensor_mut().serial.set_preheat(None);
+        assert_eq!(tracker.poll_ready().map(|m| m.co2_ppm), Ok(800));
+        // The phase doesn't change back
+        tracker.sensor_mut().serial.set_preheat(Some(410));
+        assert_eq!(phase(&mut tracker), Phase::Valid);
+    }
+
+    #[test]
+    fn warming_status() {
+        let mut tracker = tracker(WarmupConfig::default().warming_status(Some(0x40)));
+        tracker.sensor_mut().serial.set_status(0x40);
+        tracker.set_powered_for_ms(200_000);
+        assert_eq!(phase(&mut tracker), Phase::Warming);
+
+        tracker.sensor_mut().serial.set_status(0);
+        assert_eq!(phase(&mut tracker), Phase::Valid);
+    }
+
+    #[test]
+    fn custom_sentinels() {
+        const SENTINELS: &[Concentration] = &[Concentration::from_ppm(800)];
+        let mut tracker = tracker(WarmupConfig::default().sentinels(SENTINELS));
+        tracker.set_powered_for_ms(200_000);
+        assert_eq!(phase(&mut tracker), Phase::Warming);
+    }
+
+    #[test]
+    fn wait_ready_polls_until_max_preheat_time() {
+        let mut tracker = tracker(WarmupConfig::default());
+        tracker.sensor_mut().serial.set_preheat(Some(500));
+        tracker.set_powered_for_ms(299_900);
+        let mut polls = 0;
+        let measurement = tracker.wait_ready(1000, |ms| {
+            assert_eq!(ms, 1000);
+            polls += 1;
+        });
+        assert_eq!(measurement.map(|m| m.co2_ppm), Ok(500));
+        assert!(polls > 0);
+        assert!(tracker.elapsed_ms() >= 300_000);
+    }
+
+    #[test]
+    fn wait_ready_ignores_errors_while_preheating() {
+        let mut tracker = tracker(WarmupConfig::default());
+        tracker.sensor_mut().serial.set_bootloader_frames(2);
+        tracker.set_powered_for_ms(200_000);
+        let mut polls = 0;
+        let measurement = tracker.wait_ready(1000, |_| polls += 1);
+        assert_eq!(measurement.map(|m| m.co2_ppm), Ok(800));
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn wait_ready_fails_after_max_preheat_time() {
+        let mut tracker = tracker(WarmupConfig::default());
+        tracker.sensor_mut().serial.set_bootloader_frames(1);
+        tracker.set_powered_for_ms(300_000);
+        let mut polls = 0;
+        assert_eq!(tracker.wait_ready(1000, |_| polls += 1), Err(Error::Timeout));
+        assert_eq!(polls, 0);
+    }
+}